@@ -0,0 +1,293 @@
+use std::future::Future;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    Error, HttpMessage, ResponseError,
+};
+use serde::Serialize;
+
+use crate::{
+    models::{ApiAuth, Role},
+    webhooks::constant_time_eq,
+    ApiMessage,
+};
+
+/// A user or token which made a request
+#[derive(Serialize, Clone, Debug)]
+pub struct Principal {
+    /// username, or name of the token
+    pub name: String,
+    pub role: Role,
+}
+
+/// Why a request was refused
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credentials, or wrong ones
+    Unauthorized,
+    /// Valid credentials, but the role may not do this
+    Forbidden,
+}
+
+impl ApiAuth {
+    /// Find who an `Authorization` header belongs to, either
+    /// `Bearer <token>` or `Basic <base64 of username:password>`
+    pub fn authenticate(&self, header: &str) -> Option<Principal> {
+        let (scheme, credentials) = header.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self
+                .tokens
+                .iter()
+                .find(|t| constant_time_eq(t.token.value().as_bytes(), credentials.as_bytes()))
+                .map(|t| Principal {
+                    name: t.name.clone(),
+                    role: t.role,
+                });
+        }
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            let user = self.users.iter().find(|u| u.username == username)?;
+            if constant_time_eq(user.password.value().as_bytes(), password.as_bytes()) {
+                return Some(Principal {
+                    name: user.username.clone(),
+                    role: user.role,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Middleware checking the credentials of a request before it is passed on
+/// to `srv`. Requests which may not be made are answered with `401` or `403`.
+/// Who made a request is added to its extensions as a `Principal`. Every
+/// request may be made if `auth` is not set.
+pub fn check_credentials<S>(
+    auth: Option<&ApiAuth>,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let checked = match auth {
+        Some(auth) => check_request(auth, &req),
+        None => Ok(None),
+    };
+    let result = match checked {
+        Ok(principal) => {
+            if let Some(p) = principal {
+                req.extensions_mut().insert(p);
+            }
+            Ok(srv.call(req))
+        }
+        Err(err) => Err(req.into_response(ApiMessage::from(err).error_response())),
+    };
+    async move {
+        match result {
+            Ok(fut) => fut.await,
+            Err(response) => Ok(response),
+        }
+    }
+}
+
+/// Check that a request may be made with its `Authorization` header. Returns
+/// who made it, if the header is valid.
+fn check_request(auth: &ApiAuth, req: &ServiceRequest) -> Result<Option<Principal>, AuthError> {
+    let header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    authorize(auth, req.method(), route_pattern(req).as_deref(), header)
+}
+
+/// Pattern of the route which a request is sent to, like `/jobs/{jobId}`.
+/// The router matches routes against the percent-decoded path, so the route
+/// is looked up with that path here too, and not with the raw one.
+fn route_pattern(req: &ServiceRequest) -> Option<String> {
+    req.resource_map().match_pattern(req.match_info().path())
+}
+
+/// Role needed for a request to the route with `pattern`, or `None` for
+/// requests anyone may make: the web UI itself, `GET /whoami`, and webhooks,
+/// which are signed instead. Requests which match no route, or a route not
+/// listed here, need the admin role.
+pub fn required_role(method: &Method, pattern: Option<&str>) -> Option<Role> {
+    let pattern = match pattern {
+        Some(p) => p,
+        None => return Some(Role::Admin),
+    };
+    match (method, pattern) {
+        (&Method::GET, "/") | (&Method::GET, "/whoami") | (&Method::POST, "/hooks/{jobId}") => None,
+        (&Method::GET, "/jobs")
+        | (&Method::GET, "/jobs/{jobId}")
+        | (&Method::GET, "/jobs/{jobId}/branches/{branch}")
+        | (&Method::GET, "/jobs/{jobId}/branches/{branch}/builds/{build_num}/log") => {
+            Some(Role::Viewer)
+        }
+        // polling, starting and aborting builds
+        (&Method::POST, "/jobs/{jobId}/poll")
+        | (&Method::POST, "/jobs/{jobId}/branches/{branch}/builds")
+        | (&Method::DELETE, "/jobs/{jobId}/branches/{branch}/builds/{build_num}") => {
+            Some(Role::Operator)
+        }
+        // adding and removing jobs
+        _ => Some(Role::Admin),
+    }
+}
+
+/// Check that a request to the route with `pattern` may be made with its
+/// `Authorization` header. Returns who made it, if the header is valid.
+pub fn authorize(
+    auth: &ApiAuth,
+    method: &Method,
+    pattern: Option<&str>,
+    header: Option<&str>,
+) -> Result<Option<Principal>, AuthError> {
+    let principal = header.and_then(|h| auth.authenticate(h));
+    match (required_role(method, pattern), &principal) {
+        (None, _) => Ok(principal),
+        (Some(_), None) => Err(AuthError::Unauthorized),
+        (Some(required), Some(p)) if p.role < required => Err(AuthError::Forbidden),
+        (Some(_), Some(_)) => Ok(principal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ApiToken, SecretValue};
+    use actix_web::{http::StatusCode, test, web, App, HttpRequest, HttpResponse};
+
+    fn auth() -> ApiAuth {
+        let token = |name: &str, role| ApiToken {
+            name: name.into(),
+            token: SecretValue::Plain(format!("{}-token-0123456789", name)),
+            role,
+        };
+        ApiAuth {
+            tokens: vec![
+                token("viewer", Role::Viewer),
+                token("operator", Role::Operator),
+                token("admin", Role::Admin),
+            ],
+            users: vec![],
+        }
+    }
+
+    fn bearer(name: &str) -> String {
+        format!("Bearer {}-token-0123456789", name)
+    }
+
+    #[test]
+    fn required_role_of_routes() {
+        assert_eq!(required_role(&Method::GET, Some("/")), None);
+        assert_eq!(required_role(&Method::GET, Some("/whoami")), None);
+        assert_eq!(required_role(&Method::POST, Some("/hooks/{jobId}")), None);
+        assert_eq!(
+            required_role(&Method::GET, Some("/jobs/{jobId}")),
+            Some(Role::Viewer)
+        );
+        assert_eq!(
+            required_role(
+                &Method::POST,
+                Some("/jobs/{jobId}/branches/{branch}/builds")
+            ),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            required_role(&Method::POST, Some("/jobs")),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            required_role(&Method::DELETE, Some("/jobs/{jobId}")),
+            Some(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn unknown_routes_need_admin() {
+        assert_eq!(required_role(&Method::GET, None), Some(Role::Admin));
+        assert_eq!(
+            required_role(&Method::POST, Some("/%6Aobs")),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            required_role(&Method::PUT, Some("/jobs")),
+            Some(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn authorize_checks_role() {
+        let auth = auth();
+        let create = |header: Option<&str>| {
+            authorize(&auth, &Method::POST, Some("/jobs"), header).map(|p| p.map(|p| p.name))
+        };
+        assert_eq!(create(None), Err(AuthError::Unauthorized));
+        assert_eq!(create(Some("Bearer wrong")), Err(AuthError::Unauthorized));
+        assert_eq!(create(Some(&bearer("operator"))), Err(AuthError::Forbidden));
+        assert_eq!(create(Some(&bearer("admin"))), Ok(Some("admin".into())));
+        assert_eq!(
+            authorize(&auth, &Method::GET, Some("/whoami"), None).map(|p| p.is_none()),
+            Ok(true)
+        );
+    }
+
+    /// Name of the caller added to the request by `check_credentials`
+    async fn caller(req: HttpRequest) -> HttpResponse {
+        match req.extensions().get::<Principal>() {
+            Some(principal) => HttpResponse::Ok().body(principal.name.clone()),
+            None => HttpResponse::Ok().finish(),
+        }
+    }
+
+    /// Requests with percent-encoded paths are routed to the same handlers as
+    /// plain ones, and need the same role
+    #[test]
+    fn encoded_paths_need_the_role_of_their_route() {
+        actix_web::rt::System::new("test").block_on(async {
+            let auth = auth();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap_fn(move |req, srv| check_credentials(Some(&auth), req, srv))
+                    .route("/jobs", web::post().to(HttpResponse::Ok))
+                    .route("/jobs/{jobId}", web::get().to(caller))
+                    .route("/jobs/{jobId}", web::delete().to(HttpResponse::Ok)),
+            )
+            .await;
+            let request = |method: Method, path: &str, token: &str| {
+                test::TestRequest::with_uri(path)
+                    .method(method)
+                    .header("authorization", bearer(token))
+                    .to_request()
+            };
+            for (method, path, token, expected) in [
+                (Method::POST, "/%6Aobs", "viewer", StatusCode::FORBIDDEN),
+                (Method::POST, "/%6Aobs", "admin", StatusCode::OK),
+                (
+                    Method::DELETE,
+                    "/%6Aobs/x",
+                    "operator",
+                    StatusCode::FORBIDDEN,
+                ),
+                (
+                    Method::DELETE,
+                    "/jobs/%78",
+                    "operator",
+                    StatusCode::FORBIDDEN,
+                ),
+                (Method::GET, "/%6Aobs/x", "viewer", StatusCode::OK),
+            ] {
+                let response = test::call_service(&mut app, request(method, path, token)).await;
+                assert_eq!(response.status(), expected, "{}", path);
+            }
+            let response =
+                test::call_service(&mut app, request(Method::GET, "/jobs/x", "viewer")).await;
+            assert_eq!(test::read_body(response).await, "viewer");
+        });
+    }
+}
@@ -1,106 +1,441 @@
 use actix::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{create_dir_all, remove_dir_all, File},
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    fs::{create_dir_all, read_dir, remove_dir_all, File},
+    io::BufReader,
+    path::{Path, PathBuf},
 };
 
-use crate::{build_actor::BuildActor, git_utils::clone_commit, models::Job};
+use crate::{
+    build_actor::{log_thingy_line, lossy_lines, BuildActor, BuildInfo, StopBuildMessage},
+    git_utils::head_commit,
+    git_worker::{CheckedOutMsg, CheckoutMsg, GitWorker, ListRefsMsg, RefsListedMsg},
+    models::{
+        BuildDetails, BuildStatus, BuildTrigger, GitRef, Job, NewCommitPolicy, RefKind,
+        ResumePolicy,
+    },
+    persist::{backup_path, read_with_backup, write_atomic},
+    thingy::{BuildDoneMsg, QueueBuildMsg, Thingy},
+};
 
 #[derive(Debug)]
 pub struct BranchActor {
     job: Job,
+    /// name under which the ref is tracked, see `GitRef::key`
     branch: String,
+    git_ref: GitRef,
     dir: PathBuf,
+    /// the job's bare mirror of the repository, see `CheckoutMode::Mirror`
+    mirror_dir: PathBuf,
+    root: Addr<Thingy>,
+    /// runs git operations, off the actor's thread
+    git: Addr<GitWorker>,
     builds: Vec<BuildLink>,
+    /// builds waiting for a build slot, oldest first
+    queued: Vec<QueuedBuild>,
+    /// builds whose repository is being checked out by the git workers
+    checking_out: Vec<QueuedBuild>,
+    /// number of the latest build, as saved in build_num.txt
+    last_build_num: u64,
+    /// trigger of a commit which was not built because of `skip_if_running`.
+    /// The ref is checked again once no build is in flight.
+    skipped_trigger: Option<BuildTrigger>,
     state: BranchDetails,
 }
 
 impl BranchActor {
-    pub fn new(job: Job, branch: String, dir: PathBuf, last_seen_commit: Option<String>) -> Self {
+    pub fn new(
+        job: Job,
+        git_ref: GitRef,
+        dir: PathBuf,
+        mirror_dir: PathBuf,
+        root: Addr<Thingy>,
+        git: Addr<GitWorker>,
+        last_seen_commit: Option<String>,
+    ) -> Self {
         BranchActor {
             job,
-            branch,
+            branch: git_ref.key(),
+            git_ref: git_ref.clone(),
             dir,
+            mirror_dir,
+            root,
+            git,
             builds: vec![],
+            queued: vec![],
+            checking_out: vec![],
+            last_build_num: 0,
+            skipped_trigger: None,
             state: BranchDetails {
+                git_ref: Some(git_ref),
                 last_seen_commit,
+                pending_commit: None,
+                pending_trigger: None,
+                archived: false,
                 builds: vec![],
             },
         }
     }
 
     fn inc_build_num(&mut self) -> Result<u64, std::io::Error> {
-        let build_num_file = self.dir.join("build_num.txt");
-        let next_num: u64 = if build_num_file.exists() {
-            let n: u64 = std::fs::read_to_string(&build_num_file)?
-                .parse()
-                .unwrap_or_default();
-            n + 1
-        } else {
-            1
-        };
-        std::fs::write(build_num_file, format!("{}", next_num).as_bytes())?;
+        let next_num = self.last_build_num + 1;
+        write_atomic(
+            &self.get_build_num_path(),
+            format!("{}", next_num).as_bytes(),
+        )?;
+        self.last_build_num = next_num;
         Ok(next_num)
     }
 
+    /// Load data.json and build_num.txt. Unreadable files are replaced by
+    /// their previous versions, or rebuilt from the build directories.
+    fn load_state(&mut self) -> Result<(), std::io::Error> {
+        let data_path = self.get_data_path();
+        let mut save_data = false;
+        if data_path.exists() || backup_path(&data_path).exists() {
+            let details = read_with_backup(&data_path, |s| {
+                serde_json::from_str::<BranchDetails>(s).map_err(|e| e.to_string())
+            });
+            match details {
+                Ok(details) => self.state = details,
+                Err(err) => {
+                    eprintln!(
+                        "{}. Rebuilding the history of {}/{} from its build directories.",
+                        err, self.job.name, self.branch
+                    );
+                    self.state.builds = recover_builds(&self.dir);
+                    self.state.last_seen_commit = self
+                        .state
+                        .builds
+                        .iter()
+                        .rev()
+                        .find_map(|b| b.commit_hash.clone());
+                    save_data = true;
+                }
+            }
+            // not saved by older versions of thingy
+            self.state.git_ref = Some(self.git_ref.clone());
+        } else {
+            save_data = true;
+        }
+        if save_data {
+            self.write_data_file()?;
+        }
+
+        // build numbers are never reused, even if build_num.txt was lost
+        let build_num_path = self.get_build_num_path();
+        let saved = read_with_backup(&build_num_path, |s| {
+            s.trim().parse::<u64>().map_err(|e| e.to_string())
+        });
+        if let (Err(err), true) = (&saved, build_num_path.exists()) {
+            eprintln!("{}", err);
+        }
+        let saved = saved.ok();
+        self.last_build_num = build_dir_nums(&self.dir)
+            .into_iter()
+            .chain(self.state.builds.iter().map(|b| b.build_num))
+            .chain(saved)
+            .max()
+            .unwrap_or(0);
+        if saved != Some(self.last_build_num) {
+            write_atomic(
+                &build_num_path,
+                format!("{}", self.last_build_num).as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Queue a new build, and return its number. It is started once the root
+    /// actor has a free build slot for it.
     fn start_build(
         &mut self,
-        _ctx: &mut Context<Self>,
+        ctx: &mut Context<Self>,
         hash: Option<String>,
-    ) -> Result<(), std::io::Error> {
+        trigger: BuildTrigger,
+    ) -> Result<u64, std::io::Error> {
         let bn = self.inc_build_num()?;
-        // start a build, update last_seen
-        let build_dir = self.dir.join(&format!("{}", bn));
-        if build_dir.exists() {
-            remove_dir_all(&build_dir)?;
-        }
-        create_dir_all(&build_dir)?;
-        let checkout_dir = build_dir.join("repo");
-        create_dir_all(&checkout_dir)?;
-        // do build
-        if let Ok(_) = clone_commit(
-            &self.job.repo_url,
-            &self.branch,
-            hash.clone(),
-            &checkout_dir,
-            self.job.auth.as_ref(),
-        ) {
-            let h = BuildActor::new(
-                self.job.build_script.clone(),
-                checkout_dir.clone(),
-                hash.clone(),
-                _ctx.address(),
-                build_dir.join("log.txt"),
-                bn,
-            )
-            .start();
-            self.builds.push(BuildLink {
-                build_num: bn,
-                addr: h,
-            });
-        }
+        let previous_commit_hash = self.state.builds.last().and_then(|b| b.commit_hash.clone());
+        self.queued.push(QueuedBuild {
+            build_num: bn,
+            commit_hash: hash.clone(),
+            previous_commit_hash,
+            trigger,
+        });
         if hash.is_some() {
             self.state.last_seen_commit = hash.clone();
         }
-        let build = BuildDetails {
+        self.state
+            .builds
+            .push(BuildDetails::new(bn, hash, trigger, BuildStatus::Queued));
+        self.write_data_file()?;
+        self.root.do_send(QueueBuildMsg {
+            job: self.job.name.clone(),
+            branch: self.branch.clone(),
             build_num: bn,
-            commit_hash: hash,
-            status: "building".into(),
+            addr: ctx.address(),
+        });
+        Ok(bn)
+    }
+
+    /// Mark builds which were queued or running when an earlier thingy
+    /// process stopped as interrupted, and build them again if the job's
+    /// `resume_on_restart` says so
+    fn recover_interrupted_builds(
+        &mut self,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), std::io::Error> {
+        let interrupted: Vec<BuildDetails> = self
+            .state
+            .builds
+            .iter()
+            .filter(|b| b.is_orphaned())
+            .cloned()
+            .collect();
+        let rebuild_all = self.job.on_new_commit == NewCommitPolicy::Parallel;
+        for (idx, build) in interrupted.iter().enumerate() {
+            let bn = build.build_num;
+            self.set_build_status(bn, BuildStatus::Interrupted);
+            let is_latest = idx + 1 == interrupted.len();
+            let mut note = "Thingy stopped before this build finished".to_string();
+            if self.job.resume_on_restart == ResumePolicy::Rebuild
+                && !self.state.archived
+                && (rebuild_all || is_latest)
+            {
+                let trigger = build.trigger.unwrap_or(BuildTrigger::Poll);
+                let new_bn = self.start_build(ctx, build.commit_hash.clone(), trigger)?;
+                note = format!("{}, it is built again as build {}", note, new_bn);
+            }
+            eprintln!(
+                "Build {} of {}/{}: {}",
+                bn, self.job.name, self.branch, note
+            );
+            // queued builds have no directory yet
+            let build_dir = self.dir.join(format!("{}", bn));
+            if build_dir.exists() {
+                log_thingy_line(&build_dir.join("log.txt"), &note);
+            }
+        }
+        if !interrupted.is_empty() {
+            self.write_data_file()?;
+        }
+        Ok(())
+    }
+
+    /// Check out the repository for a build which has been dequeued. The
+    /// build starts once the git workers report back with a `CheckedOutMsg`.
+    fn run_build(
+        &mut self,
+        ctx: &mut Context<Self>,
+        queued: QueuedBuild,
+    ) -> Result<(), std::io::Error> {
+        let bn = queued.build_num;
+        let build_dir = self.dir.join(format!("{}", bn));
+        let checkout_dir = match prepare_build_dir(&build_dir) {
+            Ok(dir) => dir,
+            Err(err) => {
+                // the build holds a build slot, which is given back
+                let error = format!("Could not create the build directory: {}", err);
+                log_thingy_line(&build_dir.join("log.txt"), &error);
+                eprintln!(
+                    "Could not start build {} of {}/{}: {}",
+                    bn, self.job.name, self.branch, error
+                );
+                self.set_build_status(bn, BuildStatus::CloneFailed { error: Some(error) });
+                self.build_done(ctx, bn)?;
+                return self.write_data_file();
+            }
         };
-        self.state.builds.push(build);
+        self.set_build_status(bn, BuildStatus::CloningRepo);
+        self.git.do_send(CheckoutMsg {
+            job: self.job.clone(),
+            git_ref: self.git_ref.clone(),
+            commit_hash: queued.commit_hash.clone(),
+            checkout_dir,
+            mirror_dir: self.mirror_dir.clone(),
+            build_num: bn,
+            reply: ctx.address().recipient(),
+        });
+        self.checking_out.push(queued);
         self.write_data_file()?;
         Ok(())
     }
 
+    /// Start a build whose repository has been checked out
+    fn checked_out(
+        &mut self,
+        ctx: &mut Context<Self>,
+        queued: QueuedBuild,
+        checkout: CheckedOutMsg,
+    ) -> Result<(), std::io::Error> {
+        let bn = queued.build_num;
+        let build_dir = self.dir.join(format!("{}", bn));
+        if let Some(commit_hash) = &checkout.commit_hash {
+            self.state
+                .builds
+                .iter_mut()
+                .filter(|b| b.build_num == bn)
+                .for_each(|b| b.commit_hash = Some(commit_hash.clone()));
+        }
+        let commit_hash = checkout.commit_hash;
+        match checkout.result.map(|_| commit_hash) {
+            Ok(Some(commit_hash)) => {
+                let info = BuildInfo {
+                    job_name: self.job.name.clone(),
+                    repo_url: self.job.repo_url.clone(),
+                    branch: self.branch.clone(),
+                    git_ref: self.git_ref.clone(),
+                    commit_hash,
+                    previous_commit_hash: queued.previous_commit_hash,
+                    build_num: bn,
+                    build_dir: build_dir.clone(),
+                    trigger: queued.trigger,
+                };
+                let h = BuildActor::new(self.job.clone(), info, ctx.address()).start();
+                self.builds.push(BuildLink {
+                    build_num: bn,
+                    addr: h,
+                });
+                self.set_build_status(bn, BuildStatus::Running);
+            }
+            result => {
+                let error = result
+                    .err()
+                    .unwrap_or_else(|| "no commit was checked out".into());
+                log_thingy_line(
+                    &build_dir.join("log.txt"),
+                    &format!("Could not check out the repository: {}", error),
+                );
+                eprintln!(
+                    "Could not check out build {} of {}/{}: {}",
+                    bn, self.job.name, self.branch, error
+                );
+                self.set_build_status(bn, BuildStatus::CloneFailed { error: Some(error) });
+                self.build_done(ctx, bn)?;
+            }
+        }
+        self.write_data_file()?;
+        Ok(())
+    }
+
+    /// Abort a queued, checking out or running build. Returns false if the
+    /// build is in none of these states.
+    fn abort_build(
+        &mut self,
+        ctx: &mut Context<Self>,
+        build_num: u64,
+        reason: &str,
+    ) -> Result<bool, std::io::Error> {
+        let status = BuildStatus::Aborted {
+            reason: Some(reason.into()),
+        };
+        if let Some(idx) = self.queued.iter().position(|q| q.build_num == build_num) {
+            self.queued.remove(idx);
+            self.set_build_status(build_num, status);
+            self.build_done(ctx, build_num)?;
+            return Ok(true);
+        }
+        // the checkout carries on, but the build will not be started
+        if let Some(idx) = self
+            .checking_out
+            .iter()
+            .position(|q| q.build_num == build_num)
+        {
+            self.checking_out.remove(idx);
+            self.set_build_status(build_num, status);
+            self.build_done(ctx, build_num)?;
+            return Ok(true);
+        }
+        if let Some(link) = self.builds.iter().find(|l| l.build_num == build_num) {
+            link.addr.do_send(StopBuildMessage {
+                reason: reason.into(),
+            });
+            // this actor may be stopped before the build reports back, so
+            // the status is saved right away
+            self.set_build_status(build_num, status);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Whether any build of this branch is queued or running
+    fn has_builds_in_flight(&self) -> bool {
+        !self.queued.is_empty() || !self.checking_out.is_empty() || !self.builds.is_empty()
+    }
+
+    /// Abort all queued and running builds
+    fn abort_all_builds(
+        &mut self,
+        ctx: &mut Context<Self>,
+        reason: &str,
+    ) -> Result<(), std::io::Error> {
+        let build_nums: Vec<u64> = self
+            .queued
+            .iter()
+            .chain(self.checking_out.iter())
+            .map(|q| q.build_num)
+            .chain(self.builds.iter().map(|l| l.build_num))
+            .collect();
+        for bn in build_nums {
+            self.abort_build(ctx, bn, reason)?;
+        }
+        Ok(())
+    }
+
+    /// Called when a build has finished, or will not run. Frees its build
+    /// slot, and starts the pending commit, if any, once no build is in flight.
+    /// If a commit was skipped instead, the ref is checked for its latest
+    /// commit, as jobs which only get webhooks would not see it again.
+    fn build_done(
+        &mut self,
+        ctx: &mut Context<Self>,
+        build_num: u64,
+    ) -> Result<(), std::io::Error> {
+        self.release_build_slot(build_num);
+        if !self.has_builds_in_flight() {
+            if let Some(hash) = self.state.pending_commit.take() {
+                let trigger = self.state.pending_trigger.take();
+                self.start_build(ctx, Some(hash), trigger.unwrap_or(BuildTrigger::Poll))?;
+            } else if self.skipped_trigger.is_some() {
+                self.git.do_send(ListRefsMsg {
+                    job: self.job.clone(),
+                    reply: ctx.address().recipient(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn set_build_status(&mut self, build_num: u64, status: BuildStatus) {
+        self.state
+            .builds
+            .iter_mut()
+            .filter(|b| b.build_num == build_num)
+            .for_each(|b| b.set_status(status.clone()));
+    }
+
+    /// Let the root actor know that a build no longer needs a build slot
+    fn release_build_slot(&self, build_num: u64) {
+        self.root.do_send(BuildDoneMsg {
+            job: self.job.name.clone(),
+            branch: self.branch.clone(),
+            build_num,
+        });
+    }
+
     fn get_data_path(&self) -> PathBuf {
         self.dir.join("data.json")
     }
 
+    fn get_build_num_path(&self) -> PathBuf {
+        self.dir.join("build_num.txt")
+    }
+
     fn write_data_file(&self) -> Result<(), std::io::Error> {
-        std::fs::write(self.get_data_path(), serde_json::to_string(&self.state)?)?;
-        Ok(())
+        write_atomic(
+            &self.get_data_path(),
+            serde_json::to_string(&self.state)?.as_bytes(),
+        )
     }
 }
 
@@ -108,18 +443,26 @@ impl Actor for BranchActor {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Context<Self>) {
-        // create build num and data files
-        let build_num_path = self.dir.join("build_num.txt");
-        if !build_num_path.exists() {
-            std::fs::write(build_num_path, "0".as_bytes()).unwrap();
+        if let Err(err) = self.load_state() {
+            eprintln!(
+                "Could not save the state of {}/{}: {:?}",
+                self.job.name, self.branch, err
+            );
         }
-        let data_path = self.get_data_path();
-        if !data_path.exists() {
-            self.write_data_file().unwrap();
-        } else {
-            let det: BranchDetails =
-                serde_json::from_str(std::fs::read_to_string(data_path).unwrap().as_str()).unwrap();
-            self.state = det;
+        if let Err(err) = self.recover_interrupted_builds(_ctx) {
+            eprintln!("Could not recover interrupted builds: {:?}", err);
+        }
+        // a commit was waiting for builds which are not running any more. If
+        // they are built again, it waits for them to finish.
+        if !self.has_builds_in_flight() {
+            if let Some(hash) = self.state.pending_commit.take() {
+                let trigger = self.state.pending_trigger.take();
+                if let Err(err) =
+                    self.start_build(_ctx, Some(hash), trigger.unwrap_or(BuildTrigger::Poll))
+                {
+                    eprintln!("Could not start build of pending commit: {:?}", err);
+                }
+            }
         }
     }
 
@@ -128,13 +471,13 @@ impl Actor for BranchActor {
 
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), std::io::Error>")]
-pub struct NewCommitMsg(pub String);
+pub struct NewCommitMsg(pub String, pub BuildTrigger);
 
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), std::io::Error>")]
 pub struct BuildStoppedMsg {
     pub build_num: u64,
-    pub status: String,
+    pub status: BuildStatus,
 }
 
 #[derive(Message, Debug)]
@@ -147,17 +490,147 @@ struct BuildLink {
     addr: Addr<BuildActor>,
 }
 
+/// A build which has been numbered, but not started yet
+#[derive(Debug, Clone)]
+struct QueuedBuild {
+    build_num: u64,
+    commit_hash: Option<String>,
+    previous_commit_hash: Option<String>,
+    trigger: BuildTrigger,
+}
+
+/// Sent by the root actor when a queued build may start
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct StartQueuedBuildMsg(pub u64);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BranchDetails {
+    /// the ref being built, always set once the actor has started
+    git_ref: Option<GitRef>,
     last_seen_commit: Option<String>,
+    /// newest commit waiting for builds in flight to finish, see `NewCommitPolicy::Queue`
+    pending_commit: Option<String>,
+    /// what found the pending commit
+    pending_trigger: Option<BuildTrigger>,
+    /// set when the ref no longer exists in the repository, or is no longer
+    /// built by the job. Archived branches keep their history, but are not
+    /// built.
+    #[serde(default)]
+    archived: bool,
     builds: Vec<BuildDetails>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct BuildDetails {
-    build_num: u64,
-    commit_hash: Option<String>,
-    status: String,
+/// Create an empty directory for a build, replacing any directory left by a
+/// build with the same number. Returns the directory to check out into.
+fn prepare_build_dir(build_dir: &Path) -> Result<PathBuf, std::io::Error> {
+    if build_dir.exists() {
+        remove_dir_all(build_dir)?;
+    }
+    let checkout_dir = build_dir.join("repo");
+    create_dir_all(&checkout_dir)?;
+    Ok(checkout_dir)
+}
+
+/// Numbers of the build directories of a branch. Directories of branches
+/// nested in this one, which have their own data.json, are left out.
+fn build_dir_nums(dir: &Path) -> Vec<u64> {
+    let entries = match read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter(|e| !has_data_file(&e.path()))
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse().ok()))
+        .collect()
+}
+
+/// History of a branch whose data.json was lost, from its build directories.
+/// Only the number and commit of each build can be recovered.
+fn recover_builds(dir: &Path) -> Vec<BuildDetails> {
+    let mut nums = build_dir_nums(dir);
+    nums.sort_unstable();
+    nums.into_iter()
+        .map(|n| BuildDetails {
+            build_num: n,
+            commit_hash: head_commit(&dir.join(format!("{}", n)).join("repo")),
+            trigger: None,
+            status: BuildStatus::Unknown,
+            transitions: vec![],
+            run_id: None,
+        })
+        .collect()
+}
+
+/// Whether a directory is the directory of a branch
+fn has_data_file(dir: &Path) -> bool {
+    let data_path = dir.join("data.json");
+    data_path.is_file() || backup_path(&data_path).is_file()
+}
+
+/// A branch found in a job's directory, built by an earlier thingy process
+#[derive(Debug)]
+pub struct StoredBranch {
+    pub git_ref: GitRef,
+    pub dir: PathBuf,
+    pub archived: bool,
+}
+
+/// Find the branches which have a `data.json` in a job's directory. Keys of
+/// refs can contain `/`, so branch directories can be nested in each other,
+/// and in directories which are not branches themselves.
+pub fn find_branches(job_dir: &Path) -> Vec<StoredBranch> {
+    let mut found = vec![];
+    find_branches_in(job_dir, None, &mut found);
+    found
+}
+
+fn find_branches_in(dir: &Path, key: Option<&str>, found: &mut Vec<StoredBranch>) {
+    let is_branch = key.is_some() && has_data_file(dir);
+    if let (true, Some(key)) = (is_branch, key) {
+        // errors are reported when the branch actor loads the file, and
+        // rebuilds unreadable state
+        let read = |p: &Path| {
+            std::fs::read_to_string(p)
+                .ok()
+                .and_then(|s| serde_json::from_str::<BranchDetails>(&s).ok())
+        };
+        let data_path = dir.join("data.json");
+        let (git_ref, archived) = match read(&data_path).or_else(|| read(&backup_path(&data_path)))
+        {
+            Some(d) => (d.git_ref, d.archived),
+            None => (None, false),
+        };
+        found.push(StoredBranch {
+            // not saved by older versions of thingy
+            git_ref: git_ref.unwrap_or_else(|| GitRef::from_key(key)),
+            dir: dir.to_path_buf(),
+            archived,
+        });
+    }
+    let entries = match read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // the job's mirror, ref names can not start with `.`
+        if name.starts_with('.') || !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        // build directories, with the checkouts of builds
+        if is_branch && name.parse::<u64>().is_ok() && !has_data_file(&path) {
+            continue;
+        }
+        let child_key = match key {
+            Some(k) => format!("{}/{}", k, name),
+            None => name,
+        };
+        find_branches_in(&path, Some(&child_key), found);
+    }
 }
 
 #[derive(Message, Debug)]
@@ -171,23 +644,94 @@ pub struct GetBuildLogLinesMsg {
 pub struct LogResponse {
     pub lines: Vec<String>,
     pub has_more: bool,
-    pub status: Option<String>,
+    pub status: Option<BuildStatus>,
 }
 
+/// Abort a queued or running build. Responds with false if no such build
+/// is queued or running.
 #[derive(Message, Debug)]
-#[rtype(result = "Result<Option<Addr<BuildActor>>, std::io::Error>")]
-pub struct GetBuildActorMsg(pub u64);
+#[rtype(result = "Result<bool, std::io::Error>")]
+pub struct AbortBuildMsg {
+    pub build_num: u64,
+    pub reason: String,
+}
 
+/// Build the head of the branch now. Responds with false if the branch is
+/// archived.
 #[derive(Message, Debug)]
-#[rtype(result = "Result<(), std::io::Error>")]
+#[rtype(result = "Result<bool, std::io::Error>")]
 pub struct BuildNowMsg;
 
+/// Archive the branch, or bring it back when the ref is found again
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct SetArchivedMsg(pub bool);
+
+/// Abort all running builds of this branch, with the given reason
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct AbortBuildsMsg(pub String);
+
 impl Handler<BuildNowMsg> for BranchActor {
-    type Result = Result<(), std::io::Error>;
+    type Result = Result<bool, std::io::Error>;
 
     fn handle(&mut self, _msg: BuildNowMsg, ctx: &mut Self::Context) -> Self::Result {
-        self.start_build(ctx, None)?;
-        Ok(())
+        if self.state.archived {
+            return Ok(false);
+        }
+        self.start_build(ctx, None, BuildTrigger::Manual)?;
+        Ok(true)
+    }
+}
+
+impl Handler<SetArchivedMsg> for BranchActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: SetArchivedMsg, _ctx: &mut Self::Context) -> Self::Result {
+        if self.state.archived == msg.0 {
+            return Ok(());
+        }
+        self.state.archived = msg.0;
+        self.write_data_file()
+    }
+}
+
+impl Handler<AbortBuildsMsg> for BranchActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: AbortBuildsMsg, ctx: &mut Self::Context) -> Self::Result {
+        self.state.pending_commit = None;
+        self.state.pending_trigger = None;
+        self.abort_all_builds(ctx, &msg.0)?;
+        self.write_data_file()
+    }
+}
+
+impl Handler<AbortBuildMsg> for BranchActor {
+    type Result = Result<bool, std::io::Error>;
+
+    fn handle(&mut self, msg: AbortBuildMsg, ctx: &mut Self::Context) -> Self::Result {
+        let found = self.abort_build(ctx, msg.build_num, &msg.reason)?;
+        self.write_data_file()?;
+        Ok(found)
+    }
+}
+
+impl Handler<StartQueuedBuildMsg> for BranchActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: StartQueuedBuildMsg, ctx: &mut Self::Context) -> Self::Result {
+        match self.queued.iter().position(|q| q.build_num == msg.0) {
+            Some(idx) => {
+                let queued = self.queued.remove(idx);
+                self.run_build(ctx, queued)
+            }
+            None => {
+                // build was aborted while the slot was being granted
+                self.release_build_slot(msg.0);
+                Ok(())
+            }
+        }
     }
 }
 
@@ -196,17 +740,87 @@ impl Handler<NewCommitMsg> for BranchActor {
 
     fn handle(&mut self, msg: NewCommitMsg, ctx: &mut Self::Context) -> Self::Result {
         let hash = Some(msg.0.clone());
-        if !self.state.last_seen_commit.eq(&hash) {
-            self.start_build(ctx, hash)?;
+        if self.state.last_seen_commit.eq(&hash) {
+            return Ok(());
+        }
+        // tags are built once, tags which are moved later are not rebuilt
+        if self.git_ref.kind == RefKind::Tag && self.state.last_seen_commit.is_some() {
+            return Ok(());
         }
+        if self.has_builds_in_flight() {
+            match self.job.on_new_commit {
+                NewCommitPolicy::Parallel => {}
+                NewCommitPolicy::Queue => {
+                    // replaces any older pending commit, which will not be built
+                    self.state.last_seen_commit = hash.clone();
+                    self.state.pending_commit = hash;
+                    self.state.pending_trigger = Some(msg.1);
+                    return self.write_data_file();
+                }
+                NewCommitPolicy::CancelRunning => {
+                    let reason = format!("superseded by commit {}", msg.0);
+                    self.abort_all_builds(ctx, &reason)?;
+                }
+                NewCommitPolicy::SkipIfRunning => {
+                    // the commit is not recorded as seen, so that it is
+                    // built if it is still the latest one later
+                    self.skipped_trigger = Some(msg.1);
+                    return Ok(());
+                }
+            }
+        }
+        self.start_build(ctx, hash, msg.1)?;
         Ok(())
     }
 }
 
+/// Latest commit of the ref, checked after a commit was skipped
+impl Handler<RefsListedMsg> for BranchActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: RefsListedMsg, ctx: &mut Self::Context) -> Self::Result {
+        let trigger = match self.skipped_trigger.take() {
+            Some(t) if !self.state.archived => t,
+            _ => return Ok(()),
+        };
+        match msg.0 {
+            Ok(refs) => {
+                if let Some(hash) = refs.get(&self.git_ref) {
+                    ctx.notify(NewCommitMsg(hash.clone(), trigger));
+                }
+            }
+            Err(err) => eprintln!(
+                "Could not check {}/{} for skipped commits: {}",
+                self.job.name, self.branch, err
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl Handler<CheckedOutMsg> for BranchActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: CheckedOutMsg, ctx: &mut Self::Context) -> Self::Result {
+        match self
+            .checking_out
+            .iter()
+            .position(|q| q.build_num == msg.build_num)
+        {
+            Some(idx) => {
+                let queued = self.checking_out.remove(idx);
+                self.checked_out(ctx, queued, msg)
+            }
+            // aborted during the checkout
+            None => Ok(()),
+        }
+    }
+}
+
 impl Handler<BuildStoppedMsg> for BranchActor {
     type Result = Result<(), std::io::Error>;
 
-    fn handle(&mut self, msg: BuildStoppedMsg, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: BuildStoppedMsg, ctx: &mut Self::Context) -> Self::Result {
         self.builds = self
             .builds
             .clone()
@@ -217,9 +831,8 @@ impl Handler<BuildStoppedMsg> for BranchActor {
             .builds
             .iter_mut()
             .filter(|b| b.build_num == msg.build_num)
-            .for_each(|b| {
-                b.status = msg.status.clone();
-            });
+            .for_each(|b| b.set_status(msg.status.clone()));
+        self.build_done(ctx, msg.build_num)?;
         self.write_data_file()?;
         Ok(())
     }
@@ -242,11 +855,7 @@ impl Handler<GetBuildLogLinesMsg> for BranchActor {
             let file = File::open(&log_file)?;
             let reader = BufReader::new(file);
 
-            let lines = reader
-                .lines()
-                .filter(|l| l.is_ok())
-                .map(|l| l.unwrap())
-                .skip(_msg.start as usize);
+            let lines = lossy_lines(reader).skip(_msg.start as usize);
             let mut batch: Vec<String> = lines.take(_msg.num_lines as usize + 1).collect();
             let has_more = batch.len() >= _msg.num_lines as usize;
             if has_more {
@@ -271,15 +880,3 @@ impl Handler<GetBuildLogLinesMsg> for BranchActor {
         })
     }
 }
-
-impl Handler<GetBuildActorMsg> for BranchActor {
-    type Result = Result<Option<Addr<BuildActor>>, std::io::Error>;
-
-    fn handle(&mut self, msg: GetBuildActorMsg, _ctx: &mut Self::Context) -> Self::Result {
-        Ok(self
-            .builds
-            .iter()
-            .find(|l| l.build_num == msg.0)
-            .map(|a| a.addr.clone()))
-    }
-}
@@ -1,45 +1,289 @@
 use actix::prelude::*;
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
 use std::io::prelude::*;
 use std::{
     fs::OpenOptions,
     io::{BufRead, BufReader},
-    path::PathBuf,
-    process::{Child, Command, Stdio},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
     writeln,
 };
 
-use crate::branch_actor::{BranchActor, BuildStoppedMsg};
+use crate::{
+    branch_actor::{BranchActor, BuildStoppedMsg},
+    models::{read_env_file, BuildStatus, BuildTrigger, GitRef, Job, RefKind},
+    secrets::MASTER_KEY_VARS,
+};
+
+/// Variables passed on to builds of jobs with `clear_env` set
+const MINIMAL_ENV: &[&str] = &[
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "LANG", "LC_ALL", "TZ", "TMPDIR",
+];
+
+/// How often a running build's process is checked for exit
+const PROCESS_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between SIGTERM and SIGKILL when a job does not configure it
+const DEFAULT_KILL_GRACE_SECONDS: u64 = 10;
+
+/// Secret values shorter than this are not masked in logs, they would
+/// match too much
+const MIN_MASKED_LEN: usize = 4;
+
+/// Information about what is being built, passed on to build scripts as
+/// environment variables
+#[derive(Debug, Clone)]
+pub struct BuildInfo {
+    pub job_name: String,
+    pub repo_url: String,
+    /// name under which the ref is tracked, see `GitRef::key`
+    pub branch: String,
+    pub git_ref: GitRef,
+    pub commit_hash: String,
+    /// commit built by the previous build of this branch, if any
+    pub previous_commit_hash: Option<String>,
+    pub build_num: u64,
+    /// directory of this build, containing the checkout and logs
+    pub build_dir: PathBuf,
+    pub trigger: BuildTrigger,
+}
+
+impl BuildInfo {
+    /// Environment variables for the build script
+    fn env_vars(&self, checkout_dir: &Path) -> Vec<(&'static str, String)> {
+        vec![
+            ("BRANCH", self.branch.clone()),
+            ("REF_KIND", self.git_ref.kind.as_str().into()),
+            ("REF_NAME", self.git_ref.refname.clone()),
+            ("TAG_NAME", self.short_name_if(RefKind::Tag)),
+            (
+                "PULL_REQUEST_NUMBER",
+                self.short_name_if(RefKind::PullRequest),
+            ),
+            ("COMMIT_HASH", self.commit_hash.clone()),
+            (
+                "PREVIOUS_COMMIT_HASH",
+                self.previous_commit_hash.clone().unwrap_or_default(),
+            ),
+            ("BUILD_NUMBER", self.build_num.to_string()),
+            ("JOB_NAME", self.job_name.clone()),
+            ("REPO_URL", self.repo_url.clone()),
+            ("WORKSPACE_DIR", checkout_dir.to_string_lossy().into()),
+            ("BUILD_DIR", self.build_dir.to_string_lossy().into()),
+            ("TRIGGER", self.trigger.as_str().into()),
+        ]
+    }
+
+    fn short_name_if(&self, kind: RefKind) -> String {
+        if self.git_ref.kind == kind {
+            self.git_ref.short_name().into()
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// Replaces secret values in build log lines with `***`
+#[derive(Debug, Clone, Default)]
+struct LogMasker {
+    /// secret values and their base64 forms, longest first
+    patterns: Vec<String>,
+}
+
+impl LogMasker {
+    fn new(secrets: Vec<String>) -> Self {
+        let mut patterns = vec![];
+        // lines of multi-line secrets, like private keys, are printed one
+        // by one, so each line is masked on its own
+        for value in secrets.iter().flat_map(|s| s.lines()) {
+            let value = value.trim();
+            if value.len() < MIN_MASKED_LEN {
+                continue;
+            }
+            patterns.push(value.to_string());
+            for config in [base64::STANDARD_NO_PAD, base64::URL_SAFE_NO_PAD] {
+                patterns.push(base64::encode_config(value, config));
+            }
+        }
+        patterns.sort_by_key(|p| std::cmp::Reverse(p.len()));
+        patterns.dedup();
+        LogMasker { patterns }
+    }
+
+    fn mask(&self, line: &str) -> String {
+        let mut line = line.to_string();
+        for p in &self.patterns {
+            if line.contains(p.as_str()) {
+                line = line.replace(p.as_str(), "***");
+            }
+        }
+        line
+    }
+}
 
 #[derive(Debug)]
 pub struct BuildActor {
-    command: String,
+    job: Job,
+    info: BuildInfo,
+    masker: LogMasker,
+    /// checkout directory, the build script runs here
     dir: PathBuf,
-    commit_hash: Option<String>,
     parent: Addr<BranchActor>,
     log_file_path: PathBuf,
     process: Option<Child>,
+    /// process group of the build, which can outlive the build process when
+    /// the build is terminated
+    pgid: Option<Pid>,
+    /// when the process group is killed, once the build has been asked to
+    /// terminate
+    kill_deadline: Option<Instant>,
     num: u64,
-    status: String,
+    status: BuildStatus,
+    /// status to report once the process exits, if the build was terminated
+    /// by thingy
+    terminated_as: Option<BuildStatus>,
 }
 
 impl BuildActor {
-    pub fn new(
-        command: String,
-        dir: PathBuf,
-        commit_hash: Option<String>,
-        parent: Addr<BranchActor>,
-        log_file_path: PathBuf,
-        num: u64,
-    ) -> Self {
+    pub fn new(job: Job, info: BuildInfo, parent: Addr<BranchActor>) -> Self {
         BuildActor {
-            command,
-            dir,
-            commit_hash,
+            masker: LogMasker::new(job.secret_strings()),
+            job,
+            dir: info.build_dir.join("repo"),
+            log_file_path: info.build_dir.join("log.txt"),
+            num: info.build_num,
+            info,
             parent,
-            log_file_path,
             process: None,
-            num,
-            status: "finished".into(),
+            pgid: None,
+            kill_deadline: None,
+            status: BuildStatus::Running,
+            terminated_as: None,
+        }
+    }
+
+    /// Append a line to the build log, used for messages from thingy itself
+    fn log(&self, line: &str) {
+        log_thingy_line(&self.log_file_path, &self.masker.mask(line));
+    }
+
+    /// Set up the environment of the build process from the job's configuration
+    fn set_env(&self, command: &mut Command) -> Result<(), String> {
+        if self.job.clear_env {
+            command.env_clear();
+            for name in MINIMAL_ENV {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        for name in MASTER_KEY_VARS {
+            command.env_remove(name);
+        }
+        // always adding this, in case the child process has something
+        // to do with python and has the insane behavior of not flushing
+        // std stream file deccriptors on print
+        command.env("PYTHONUNBUFFERED", "1");
+        if let Some(path) = &self.job.env_file {
+            command.envs(read_env_file(Path::new(path))?);
+        }
+        command.envs(self.job.env.iter().map(|(k, v)| (k, v.value())));
+        command.envs(self.info.env_vars(&self.dir));
+        Ok(())
+    }
+
+    /// Send a signal to the build's process group
+    fn signal_process_group(&self, signal: Signal) {
+        if let Some(pgid) = self.pgid {
+            if let Err(err) = killpg(pgid, signal) {
+                self.log(&format!(
+                    "Could not send {} to build process: {}",
+                    signal, err
+                ));
+            }
+        }
+    }
+
+    fn kill_grace_period(&self) -> Duration {
+        Duration::from_secs(
+            self.job
+                .kill_grace_seconds
+                .unwrap_or(DEFAULT_KILL_GRACE_SECONDS),
+        )
+    }
+
+    /// Whether any process of the build's process group is still running
+    fn process_group_alive(&self) -> bool {
+        self.pgid.is_some_and(|pgid| killpg(pgid, None).is_ok())
+    }
+
+    /// Ask the build's processes to terminate, and kill the ones which are
+    /// still running after the job's grace period, even if the build process
+    /// itself has exited by then
+    fn terminate(&mut self, ctx: &mut Context<Self>, status: BuildStatus) {
+        if self.process.is_none() || self.terminated_as.is_some() {
+            return;
+        }
+        self.terminated_as = Some(status);
+        self.signal_process_group(Signal::SIGTERM);
+        let grace = self.kill_grace_period();
+        self.kill_deadline = Some(Instant::now() + grace);
+        ctx.run_later(grace, |act, _ctx| {
+            if act.process_group_alive() {
+                act.log("Build processes did not terminate, killing them");
+                act.signal_process_group(Signal::SIGKILL);
+            }
+        });
+    }
+
+    /// Record the outcome of the build process from its exit status
+    fn set_exit_status(&mut self, status: ExitStatus) {
+        if let Some(s) = self.terminated_as.take() {
+            self.status = s;
+            return;
+        }
+        self.status = if status.success() {
+            BuildStatus::Succeeded
+        } else {
+            BuildStatus::Failed {
+                code: status.code(),
+                signal: status.signal(),
+            }
+        };
+    }
+
+    /// Check if the build process has exited, and stop the actor if it has.
+    /// A build which is being terminated is only done once the other
+    /// processes in its process group have exited too, or have been killed.
+    fn check_process(&mut self, ctx: &mut Context<Self>) {
+        if let Some(ch) = self.process.as_mut() {
+            match ch.try_wait() {
+                Ok(Some(status)) => {
+                    self.process = None;
+                    self.set_exit_status(status);
+                    self.log(&format!("Build process exited with {}", status));
+                    if self.kill_deadline.is_some() && self.process_group_alive() {
+                        self.log("Waiting for the other build processes to terminate");
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    self.log(&format!("Could not get status of build process: {}", err));
+                    ctx.stop();
+                    return;
+                }
+            }
+        }
+        if self.kill_deadline.is_none() || !self.process_group_alive() {
+            // processes a finished build left running in the background
+            // are not terminated
+            self.pgid = None;
+            ctx.stop();
         }
     }
 }
@@ -48,11 +292,15 @@ impl Actor for BuildActor {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Context<Self>) {
-        println!("Build started");
+        println!(
+            "Build {} of {}/{} started for commit {}",
+            self.num, self.info.job_name, self.info.branch, self.info.commit_hash
+        );
         let mut args: Vec<String> = self
-            .command
+            .job
+            .build_script
             .as_str()
-            .split(" ")
+            .split(' ')
             .filter(|s| !s.is_empty())
             .map(|s| s.into())
             .collect();
@@ -62,104 +310,170 @@ impl Actor for BuildActor {
 
         let mut command = Command::new(cmd);
         command.args(args);
+        if let Err(err) = self.set_env(&mut command) {
+            self.status = BuildStatus::SpawnFailed;
+            self.log(&err);
+            _ctx.stop();
+            return;
+        }
         let spawn_result = command
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            // always adding this, in case the child process has something
-            // to do with python and has the insane behavior of not flushing
-            // std stream file deccriptors on print
-            .env("PYTHONUNBUFFERED", "1")
-            // .env("BRANCH", &self.branch)
             .current_dir(&self.dir)
+            // run the build in its own process group, so that it can be
+            // terminated along with any processes it starts
+            .process_group(0)
             .spawn();
-        if let Ok(mut child) = spawn_result {
-            let std_out = child.stdout.take().unwrap();
-            let std_err = child.stderr.take().unwrap();
-            // self.process.replace(child);
-
-            // spawn threada to transfer buffers and notify actor
-            let reader = BufReader::new(std_out);
-            let log_file = self.log_file_path.clone();
-            let h = std::thread::spawn(move || {
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .create(true)
-                    .open(log_file)
-                    .unwrap();
-
-                reader
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| {
-                        let _ = writeln!(file, "[out] {}", line);
+        match spawn_result {
+            Ok(mut child) => {
+                let std_out = child.stdout.take().unwrap();
+                let std_err = child.stderr.take().unwrap();
+                self.pgid = Some(Pid::from_raw(child.id() as i32));
+                self.process.replace(child);
+
+                // spawn threads to transfer buffers into the log file, with
+                // secrets masked
+                let reader = BufReader::new(std_out);
+                let log_file = self.log_file_path.clone();
+                let masker = self.masker.clone();
+                std::thread::spawn(move || {
+                    let mut file = OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(log_file)
+                        .unwrap();
+
+                    lossy_lines(reader).for_each(|line| {
+                        let _ = writeln!(file, "[out] {}", masker.mask(&line));
                     });
-            });
-            let reader = BufReader::new(std_err);
-            let log_file = self.log_file_path.clone();
-            let h2 = std::thread::spawn(move || {
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .create(true)
-                    .open(log_file)
-                    .unwrap();
-                reader
-                    .lines()
-                    .filter_map(|line| line.ok())
-                    .for_each(|line| {
-                        let _ = writeln!(file, "[err] {}", line);
+                });
+                let reader = BufReader::new(std_err);
+                let log_file = self.log_file_path.clone();
+                let masker = self.masker.clone();
+                std::thread::spawn(move || {
+                    let mut file = OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(log_file)
+                        .unwrap();
+                    lossy_lines(reader).for_each(|line| {
+                        let _ = writeln!(file, "[err] {}", masker.mask(&line));
                     });
-            });
-            let adr = _ctx.address();
-            let _ = std::thread::spawn(move || {
-                let _ = h.join();
-                let _ = h2.join();
-                adr.do_send(StopBuildMessage);
-            });
-        } else {
-            _ctx.stop();
+                });
+                _ctx.run_interval(PROCESS_CHECK_INTERVAL, Self::check_process);
+                if let Some(timeout) = self.job.timeout_seconds {
+                    _ctx.run_later(Duration::from_secs(timeout), move |act, ctx| {
+                        act.log(&format!("Build timed out after {} seconds", timeout));
+                        act.terminate(ctx, BuildStatus::TimedOut);
+                    });
+                }
+            }
+            Err(err) => {
+                self.status = BuildStatus::SpawnFailed;
+                self.log(&format!("Could not start build script: {}", err));
+                _ctx.stop();
+            }
         }
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
-        match self.process.take() {
-            Some(mut ch) => {
-                if let Ok(Some(status)) = ch.try_wait() {
-                    self.status = if status.success() {
-                        "finished".into()
-                    } else {
-                        "error".into()
-                    };
-                } else {
-                    let _ = ch.kill();
-                    if let Ok(Some(status)) = ch.try_wait() {
-                        self.status = if status.success() {
-                            "finished".into()
-                        } else {
-                            "error".into()
-                        };
-                    }
+        if self.process.is_some() {
+            // the process is still running, so the build was stopped before
+            // it could finish
+            self.status = self.terminated_as.take().unwrap_or(BuildStatus::Aborted {
+                reason: Some("build was stopped by thingy".into()),
+            });
+            self.log("Build stopped before it finished, terminating build process");
+        }
+        if let Some(pgid) = self.pgid {
+            // the actor can not wait for the processes any more, so the
+            // process group is terminated from a separate thread
+            let deadline = match self.kill_deadline {
+                Some(deadline) => deadline,
+                None => {
+                    self.signal_process_group(Signal::SIGTERM);
+                    Instant::now() + self.kill_grace_period()
                 }
-            }
-            None => {}
+            };
+            let ch = self.process.take();
+            std::thread::spawn(move || reap_process_group(ch, pgid, deadline));
         }
         self.parent.do_send(BuildStoppedMsg {
             build_num: self.num,
             status: self.status.clone(),
         });
-        println!("Build finished");
+        println!("Build {} finished: {:?}", self.num, self.status);
+    }
+}
+
+/// Append a message from thingy itself to a build log. Each line of the
+/// message is prefixed with `[thingy]`.
+pub fn log_thingy_line(log_file_path: &Path, message: &str) {
+    if let Ok(mut file) = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log_file_path)
+    {
+        for line in message.lines() {
+            let _ = writeln!(file, "[thingy] {}", line);
+        }
     }
 }
 
+/// Lines read from `reader`, with bytes which are not valid UTF-8 replaced,
+/// so that binary or Latin-1 output does not stop the reading before the end
+/// of the input
+pub fn lossy_lines<R: BufRead>(reader: R) -> impl Iterator<Item = String> {
+    reader.split(b'\n').map_while(Result::ok).map(|mut line| {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8_lossy(&line).into_owned()
+    })
+}
+
+/// Wait for the processes of a build which have been sent SIGTERM to exit,
+/// and kill the process group if any of them are still running at `deadline`
+fn reap_process_group(mut ch: Option<Child>, pgid: Pid, deadline: Instant) {
+    while Instant::now() < deadline {
+        // the build process is waited for first, until then it stays in the
+        // process group
+        if let Some(Ok(Some(_))) = ch.as_mut().map(|c| c.try_wait()) {
+            ch = None;
+        }
+        if ch.is_none() && killpg(pgid, None).is_err() {
+            return;
+        }
+        std::thread::sleep(PROCESS_CHECK_INTERVAL);
+    }
+    let _ = killpg(pgid, Signal::SIGKILL);
+    if let Some(mut ch) = ch {
+        let _ = ch.wait();
+    }
+}
+
+/// Abort a build, terminating its process group
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), std::io::Error>")]
-pub struct StopBuildMessage;
+pub struct StopBuildMessage {
+    pub reason: String,
+}
 
 impl Handler<StopBuildMessage> for BuildActor {
     type Result = Result<(), std::io::Error>;
 
-    fn handle(&mut self, _msg: StopBuildMessage, _ctx: &mut Context<Self>) -> Self::Result {
-        Ok(_ctx.stop())
+    fn handle(&mut self, msg: StopBuildMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.process.is_none() {
+            _ctx.stop();
+            return Ok(());
+        }
+        self.log(&format!("Aborting build: {}", msg.reason));
+        self.terminate(
+            _ctx,
+            BuildStatus::Aborted {
+                reason: Some(msg.reason),
+            },
+        );
+        Ok(())
     }
 }
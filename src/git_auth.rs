@@ -0,0 +1,219 @@
+use git2::{Cred, CredentialType};
+use std::{error::Error, io::Write, path::Path, process::Command};
+use tempfile::NamedTempFile;
+
+use crate::models::{GitAuth, SecretValue};
+
+/// Times libgit2 may ask for credentials during one operation. It keeps
+/// asking as long as the server rejects them, so this stops it from looping.
+const MAX_CREDENTIAL_REQUESTS: u32 = 5;
+
+/// Username sent with tokens when the job does not configure one. Github
+/// accepts any username, and Gitea and Gitlab accept this one too.
+const DEFAULT_TOKEN_USERNAME: &str = "x-access-token";
+
+/// Git credential helper answering with the credentials from the environment
+/// variables set by `CliCredentials`
+const CREDENTIAL_HELPER: &str = r#"credential.helper=!f() { test "$1" = get && echo username="$THINGY_GIT_USERNAME" && echo password="$THINGY_GIT_PASSWORD"; }; f"#;
+
+/// Answers libgit2's credential requests for a job. Every configured
+/// credential is offered once, libgit2 asking again means it was rejected.
+pub struct CredentialProvider<'a> {
+    auth: Option<&'a GitAuth>,
+    /// credential types which have been offered already
+    offered: CredentialType,
+    requests: u32,
+}
+
+impl<'a> CredentialProvider<'a> {
+    pub fn new(auth: Option<&'a GitAuth>) -> Self {
+        CredentialProvider {
+            auth,
+            offered: CredentialType::empty(),
+            requests: 0,
+        }
+    }
+
+    /// Credentials for `url`, of one of the `allowed` types
+    pub fn credentials(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        self.requests += 1;
+        if self.requests > MAX_CREDENTIAL_REQUESTS {
+            return Err(auth_error(format!(
+                "Authentication with {} failed, giving up after {} attempts",
+                url, MAX_CREDENTIAL_REQUESTS
+            )));
+        }
+        let username = username_from_url.unwrap_or("git");
+        // SSH asks for the username first, if it is not part of the url
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+        let auth = match self.auth {
+            Some(a) => a,
+            None if allowed.contains(CredentialType::DEFAULT) => return self.offer_default(url),
+            None => {
+                return Err(auth_error(format!(
+                    "{} requires {} authentication, but the job has no auth configured",
+                    url,
+                    describe_types(allowed)
+                )))
+            }
+        };
+        let cred_type = match auth {
+            GitAuth::PrivateKey { .. } | GitAuth::PrivateKeyInline { .. } | GitAuth::SshAgent => {
+                CredentialType::SSH_KEY
+            }
+            GitAuth::UserPass { .. } | GitAuth::Token { .. } => CredentialType::USER_PASS_PLAINTEXT,
+        };
+        if !allowed.contains(cred_type) {
+            return Err(auth_error(format!(
+                "{} requires {} authentication, but the job is configured with {}",
+                url,
+                describe_types(allowed),
+                describe_auth(auth)
+            )));
+        }
+        if self.offered.contains(cred_type) {
+            return Err(auth_error(format!(
+                "Authentication with {} failed, the {} was rejected",
+                url,
+                describe_auth(auth)
+            )));
+        }
+        self.offered |= cred_type;
+        match auth {
+            GitAuth::PrivateKey { path, passphrase } => {
+                Cred::ssh_key(username, None, Path::new(path), passphrase_str(passphrase))
+            }
+            GitAuth::PrivateKeyInline {
+                key,
+                key_env,
+                passphrase,
+            } => Cred::ssh_key_from_memory(
+                username,
+                None,
+                &GitAuth::inline_key(key, key_env).map_err(auth_error)?,
+                passphrase_str(passphrase),
+            ),
+            GitAuth::SshAgent => Cred::ssh_key_from_agent(username),
+            GitAuth::UserPass { username, password } => {
+                Cred::userpass_plaintext(username, password.value())
+            }
+            GitAuth::Token { token, username } => Cred::userpass_plaintext(
+                username.as_deref().unwrap_or(DEFAULT_TOKEN_USERNAME),
+                token.value(),
+            ),
+        }
+    }
+
+    /// Credentials of the user running thingy, for NTLM and Negotiate
+    fn offer_default(&mut self, url: &str) -> Result<Cred, git2::Error> {
+        if self.offered.contains(CredentialType::DEFAULT) {
+            return Err(auth_error(format!(
+                "Authentication with {} failed, and the job has no auth configured",
+                url
+            )));
+        }
+        self.offered |= CredentialType::DEFAULT;
+        Cred::default()
+    }
+}
+
+fn passphrase_str(passphrase: &Option<SecretValue>) -> Option<&str> {
+    passphrase.as_ref().map(|p| p.value())
+}
+
+/// libgit2 copies the whole message of callback errors, including the class
+/// and code, so they are left out
+fn auth_error<S: AsRef<str>>(message: S) -> git2::Error {
+    git2::Error::from_str(message.as_ref())
+}
+
+fn describe_auth(auth: &GitAuth) -> &'static str {
+    match auth {
+        GitAuth::PrivateKey { .. } => "SSH private key",
+        GitAuth::PrivateKeyInline { .. } => "inline SSH private key",
+        GitAuth::SshAgent => "SSH agent",
+        GitAuth::UserPass { .. } => "username and password",
+        GitAuth::Token { .. } => "token",
+    }
+}
+
+fn describe_types(allowed: CredentialType) -> String {
+    let mut names = vec![];
+    if allowed.intersects(CredentialType::SSH_KEY | CredentialType::SSH_MEMORY) {
+        names.push("SSH key");
+    }
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        names.push("username and password or token");
+    }
+    if allowed.contains(CredentialType::DEFAULT) {
+        names.push("NTLM or Negotiate");
+    }
+    if names.is_empty() {
+        return "unsupported".into();
+    }
+    names.join(" or ")
+}
+
+/// Credentials of a job set up for the git command line tool. SSH keys are
+/// passed on through ssh options, and usernames, passwords and tokens through
+/// a credential helper which reads them from the environment.
+pub struct CliCredentials {
+    /// options for ssh, added to `GIT_SSH_COMMAND`
+    pub ssh_options: Vec<String>,
+    /// inline keys are written to a file for ssh, which is removed on drop
+    _key_file: Option<NamedTempFile>,
+}
+
+impl CliCredentials {
+    pub fn apply(auth: Option<&GitAuth>, command: &mut Command) -> Result<Self, Box<dyn Error>> {
+        let mut creds = CliCredentials {
+            ssh_options: vec![],
+            _key_file: None,
+        };
+        match auth {
+            Some(GitAuth::PrivateKey { path, .. }) => {
+                creds
+                    .ssh_options
+                    .push(format!("-i {} -o IdentitiesOnly=yes", shell_quote(path)));
+            }
+            Some(GitAuth::PrivateKeyInline { key, key_env, .. }) => {
+                let mut file = NamedTempFile::new()?;
+                writeln!(file, "{}", GitAuth::inline_key(key, key_env)?.trim_end())?;
+                creds.ssh_options.push(format!(
+                    "-i {} -o IdentitiesOnly=yes",
+                    shell_quote(&file.path().to_string_lossy())
+                ));
+                creds._key_file = Some(file);
+            }
+            Some(GitAuth::UserPass { username, password }) => {
+                use_credential_helper(command, username, password.value());
+            }
+            Some(GitAuth::Token { token, username }) => {
+                let username = username.as_deref().unwrap_or(DEFAULT_TOKEN_USERNAME);
+                use_credential_helper(command, username, token.value());
+            }
+            // ssh uses the agent at SSH_AUTH_SOCK by itself
+            Some(GitAuth::SshAgent) | None => {}
+        }
+        Ok(creds)
+    }
+}
+
+fn use_credential_helper(command: &mut Command, username: &str, password: &str) {
+    command
+        .args(["-c", "credential.helper="])
+        .args(["-c", CREDENTIAL_HELPER])
+        .env("THINGY_GIT_USERNAME", username)
+        .env("THINGY_GIT_PASSWORD", password);
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
@@ -1,38 +1,83 @@
-use crate::models::*;
-use git2::{build::RepoBuilder, Direction, FetchOptions, Repository};
-use std::{self, collections::HashMap, error::Error, path::PathBuf};
+use crate::{
+    git_auth::{shell_quote, CliCredentials, CredentialProvider},
+    host_keys::HostKeyVerifier,
+    models::*,
+};
+use git2::{
+    build::{CheckoutBuilder, CloneLocal, RepoBuilder},
+    Direction, FetchOptions, Oid, RemoteCallbacks, Repository, Submodule,
+};
+use std::{self, collections::HashMap, error::Error, path::Path, process::Command};
 use tempfile::TempDir;
 
-pub fn clone_commit(
-    url: &str,
-    branch: &str,
-    commit_hash: Option<String>,
-    dir: &PathBuf,
-    auth: Option<&GitAuth>,
-) -> Result<(), Box<dyn Error>> {
-    let mut callbacks = git2::RemoteCallbacks::new();
-    callbacks.credentials(
-        |_user: &str, user_from_url: Option<&str>, _cred: git2::CredentialType| match auth {
-            Some(a) => match a {
-                GitAuth::PrivateKey { path, passphrase } => git2::Cred::ssh_key(
-                    user_from_url.unwrap_or("git"),
-                    None,
-                    std::path::Path::new(path),
-                    passphrase.as_ref().map(|s| s.as_str()),
-                ),
-                GitAuth::UserPass { username, password } => {
-                    git2::Cred::userpass_plaintext(username, password)
-                }
+/// How to connect to a job's remote repository
+pub struct RemoteAccess<'a> {
+    pub auth: Option<&'a GitAuth>,
+    /// set if the job verifies SSH host keys
+    pub host_keys: Option<HostKeyVerifier>,
+    /// known_hosts file of the job, passed on to the git command line tool
+    pub known_hosts: Option<&'a str>,
+}
+
+impl<'a> RemoteAccess<'a> {
+    pub fn for_job(job: &'a Job) -> Result<Self, Box<dyn Error>> {
+        let host_keys = if job.verifies_host_keys() {
+            Some(HostKeyVerifier::new(
+                job.known_hosts.as_deref(),
+                &job.host_fingerprints,
+            )?)
+        } else {
+            None
+        };
+        Ok(RemoteAccess {
+            auth: job.auth.as_ref(),
+            host_keys,
+            known_hosts: job.known_hosts.as_deref(),
+        })
+    }
+}
+
+fn remote_callbacks<'a>(remote: &'a RemoteAccess) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut provider = CredentialProvider::new(remote.auth);
+    callbacks.credentials(move |url, username_from_url, allowed| {
+        provider.credentials(url, username_from_url, allowed)
+    });
+    if let Some(verifier) = &remote.host_keys {
+        callbacks.certificate_check(move |cert, host| match cert.as_hostkey() {
+            Some(key) => match key.hash_sha256() {
+                Some(hash) => verifier.verify(host, hash),
+                None => false,
             },
-            None => git2::Cred::default(),
-        },
-    );
+            // TLS certificates are rejected, libgit2 does not tell whether
+            // they are valid to this callback
+            None => false,
+        });
+    }
+    callbacks
+}
+
+fn fetch_options<'a>(remote: &'a RemoteAccess) -> FetchOptions<'a> {
     let mut fo = FetchOptions::new();
-    fo.remote_callbacks(callbacks);
+    fo.remote_callbacks(remote_callbacks(remote));
+    fo
+}
 
+/// Clone the repository into `dir` and check out `commit_hash`, or the ref's
+/// latest commit if no hash is given. Returns the hash of the checked out commit.
+pub fn clone_commit(
+    url: &str,
+    git_ref: &GitRef,
+    commit_hash: Option<String>,
+    dir: &Path,
+    remote: &RemoteAccess,
+) -> Result<String, Box<dyn Error>> {
+    if git_ref.kind != RefKind::Branch {
+        return clone_ref(url, git_ref, commit_hash, dir, remote);
+    }
     let repo = RepoBuilder::new()
-        .fetch_options(fo)
-        .branch(branch)
+        .fetch_options(fetch_options(remote))
+        .branch(git_ref.short_name())
         .clone(url, dir)?;
 
     if let Some(commit_hash) = commit_hash {
@@ -46,41 +91,261 @@ pub fn clone_commit(
         repo.set_head(&("refs/heads/".to_owned() + commit_hash))?;
     }
 
+    let head = repo.head()?.peel_to_commit()?;
+    Ok(head.id().to_string())
+}
+
+/// Clone a tag or pull request. These are not fetched by a plain clone, so
+/// the ref is fetched separately, and its commit checked out as a detached HEAD.
+fn clone_ref(
+    url: &str,
+    git_ref: &GitRef,
+    commit_hash: Option<String>,
+    dir: &Path,
+    remote: &RemoteAccess,
+) -> Result<String, Box<dyn Error>> {
+    let repo = RepoBuilder::new()
+        .fetch_options(fetch_options(remote))
+        .clone(url, dir)?;
+    let refspec = format!("+{0}:{0}", git_ref.refname);
+    repo.find_remote("origin")?.fetch(
+        &[refspec.as_str()],
+        Some(&mut fetch_options(remote)),
+        None,
+    )?;
+
+    let oid = match commit_hash {
+        Some(h) => git2::Oid::from_str(&h)?,
+        None => repo.refname_to_id(&git_ref.refname)?,
+    };
+    let commit = repo.find_object(oid, None)?.peel_to_commit()?;
+    repo.checkout_tree(commit.as_object(), None)?;
+    repo.set_head_detached(commit.id())?;
+    Ok(commit.id().to_string())
+}
+
+/// Fetch a ref into the job's bare mirror of the repository, creating the
+/// mirror if it does not exist yet. Only objects which are not in the mirror
+/// yet are downloaded.
+pub fn update_mirror(
+    url: &str,
+    git_ref: &GitRef,
+    mirror_dir: &Path,
+    remote: &RemoteAccess,
+) -> Result<(), Box<dyn Error>> {
+    let repo = match Repository::open_bare(mirror_dir) {
+        Ok(r) => r,
+        Err(_) => Repository::init_bare(mirror_dir)?,
+    };
+    let refspec = format!("+{0}:{0}", git_ref.refname);
+    repo.remote_anonymous(url)?.fetch(
+        &[refspec.as_str()],
+        Some(&mut fetch_options(remote)),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Check out `commit_hash`, or the ref's commit in the mirror if no hash is
+/// given, into `dir`. The checkout is a local clone of the mirror sharing its
+/// object files through hardlinks, or a shallow clone with `depth` commits of
+/// history. Returns the hash of the checked out commit.
+pub fn checkout_from_mirror(
+    url: &str,
+    mirror_dir: &Path,
+    git_ref: &GitRef,
+    commit_hash: Option<String>,
+    dir: &Path,
+    depth: Option<u32>,
+) -> Result<String, Box<dyn Error>> {
+    let mirror = Repository::open_bare(mirror_dir)?;
+    let oid = match commit_hash {
+        Some(h) => Oid::from_str(&h)?,
+        None => mirror.refname_to_id(&git_ref.refname)?,
+    };
+    // resolves annotated tags, and makes sure the commit has been fetched
+    let oid = mirror.find_object(oid, None)?.peel_to_commit()?.id();
+
+    let repo = match depth {
+        Some(depth) => {
+            let repo = Repository::init(dir)?;
+            let mirror_url = format!("file://{}", mirror_dir.canonicalize()?.display());
+            run_git(
+                dir,
+                &[
+                    "fetch",
+                    "--quiet",
+                    "--depth",
+                    &depth.to_string(),
+                    &mirror_url,
+                    &oid.to_string(),
+                ],
+                None,
+            )?;
+            repo.remote("origin", url)?;
+            repo
+        }
+        None => {
+            let repo = RepoBuilder::new()
+                .clone_local(CloneLocal::Local)
+                .clone(&mirror_dir.to_string_lossy(), dir)?;
+            repo.remote_set_url("origin", url)?;
+            repo
+        }
+    };
+
+    let commit = repo.find_commit(oid)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))?;
+    repo.set_head_detached(oid)?;
+    if git_ref.kind == RefKind::Branch {
+        // check out branches as a local branch, so build scripts see its name
+        let branch = repo.branch(git_ref.short_name(), &commit, true)?;
+        repo.set_head(branch.get().name().ok_or("Invalid branch name")?)?;
+    }
+    Ok(oid.to_string())
+}
+
+/// Check out the submodules of the repository in `dir`, and their own
+/// submodules if `recursive` is set
+pub fn update_submodules(
+    dir: &Path,
+    recursive: bool,
+    remote: &RemoteAccess,
+) -> Result<(), Box<dyn Error>> {
+    update_repo_submodules(&Repository::open(dir)?, recursive, remote)
+}
+
+fn update_repo_submodules(
+    repo: &Repository,
+    recursive: bool,
+    remote: &RemoteAccess,
+) -> Result<(), Box<dyn Error>> {
+    for mut sm in repo.submodules()? {
+        let sm_repo = match clone_submodule(repo, &mut sm, remote) {
+            Ok(r) => r,
+            Err(err) => {
+                return Err(format!(
+                    "Could not check out submodule {}: {}",
+                    sm.path().display(),
+                    err
+                )
+                .into())
+            }
+        };
+        if recursive {
+            update_repo_submodules(&sm_repo, true, remote)?;
+        }
+    }
+    Ok(())
+}
+
+/// Clone a submodule of a fresh checkout, and check out the commit recorded
+/// for it. `Submodule::update` is not used, because libgit2 resolves the
+/// submodule's path against the current directory there, and fails when a
+/// directory with the same name exists in it.
+fn clone_submodule(
+    repo: &Repository,
+    sm: &mut Submodule,
+    remote: &RemoteAccess,
+) -> Result<Repository, git2::Error> {
+    let commit = sm
+        .index_id()
+        .ok_or_else(|| git2::Error::from_str("no commit is recorded for the submodule"))?;
+    // init resolves urls relative to the repository's remote into the config
+    sm.init(false)?;
+    let url = repo
+        .config()?
+        .get_string(&format!("submodule.{}.url", sm.name().unwrap_or_default()))?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("the repository has no working directory"))?;
+    // nothing is checked out by the clone, the recorded commit is checked out below
+    let mut no_checkout = CheckoutBuilder::new();
+    no_checkout.dry_run();
+    let sm_repo = RepoBuilder::new()
+        .fetch_options(fetch_options(remote))
+        .with_checkout(no_checkout)
+        .clone(&url, &workdir.join(sm.path()))?;
+    sm_repo.set_head_detached(commit)?;
+    sm_repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    Ok(sm_repo)
+}
+
+/// Replace Git LFS pointer files in the checkout in `dir` with their contents
+pub fn pull_lfs(dir: &Path, remote: &RemoteAccess) -> Result<(), Box<dyn Error>> {
+    run_git(dir, &["lfs", "pull"], Some(remote))
+}
+
+/// Run the git command line tool in `dir`, for things libgit2 can not do
+fn run_git(dir: &Path, args: &[&str], remote: Option<&RemoteAccess>) -> Result<(), Box<dyn Error>> {
+    let mut command = Command::new("git");
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    let creds = CliCredentials::apply(remote.and_then(|r| r.auth), &mut command)?;
+    let mut ssh_options = creds.ssh_options.clone();
+    if let Some(path) = remote.and_then(|r| r.known_hosts) {
+        ssh_options.push(format!(
+            "-o UserKnownHostsFile={} -o StrictHostKeyChecking=yes",
+            shell_quote(path)
+        ));
+    }
+    if !ssh_options.is_empty() {
+        command.env(
+            "GIT_SSH_COMMAND",
+            format!("ssh -o BatchMode=yes {}", ssh_options.join(" ")),
+        );
+    }
+    let output = command.args(args).current_dir(dir).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
     Ok(())
 }
 
-pub fn get_branch_hashes(
+/// Message of an error from a Git operation. libgit2 errors are shown without
+/// their class and code, which mean nothing to users.
+pub fn error_message(err: &(dyn Error + 'static)) -> String {
+    match err.downcast_ref::<git2::Error>() {
+        Some(e) => e.message().to_string(),
+        None => err.to_string(),
+    }
+}
+
+/// Hash of the commit checked out in a repository, if there is one
+pub fn head_commit(dir: &Path) -> Option<String> {
+    let repo = Repository::open(dir).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// List the refs of a remote repository which thingy can build, with the
+/// commit each one points to
+pub fn get_remote_refs(
     url: &str,
-    auth: Option<&GitAuth>,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    remote: &RemoteAccess,
+) -> Result<HashMap<GitRef, String>, Box<dyn Error>> {
     let tmp_dir = TempDir::new()?;
     let repo = Repository::init(tmp_dir.path())?;
 
-    let mut callbacks = git2::RemoteCallbacks::new();
-    callbacks.credentials(
-        |_user: &str, user_from_url: Option<&str>, _cred: git2::CredentialType| match auth {
-            Some(a) => match a {
-                GitAuth::PrivateKey { path, passphrase } => git2::Cred::ssh_key(
-                    user_from_url.unwrap_or("git"),
-                    None,
-                    std::path::Path::new(path),
-                    passphrase.as_ref().map(|s| s.as_str()),
-                ),
-                GitAuth::UserPass { username, password } => {
-                    git2::Cred::userpass_plaintext(username, password)
-                }
-            },
-            None => git2::Cred::default(),
-        },
-    );
-
-    let mut remote = repo.remote("origin", url)?;
-    let connection = remote.connect_auth(Direction::Fetch, Some(callbacks), None)?;
-    let mut hashes: HashMap<String, String> = HashMap::new();
-    for b in connection.list()?.iter() {
-        if b.name().starts_with("refs/heads/") {
-            let bname = b.name()[11..].to_string();
-            hashes.insert(bname, b.oid().to_string());
+    let mut origin = repo.remote("origin", url)?;
+    let connection = origin.connect_auth(Direction::Fetch, Some(remote_callbacks(remote)), None)?;
+    let mut hashes: HashMap<GitRef, String> = HashMap::new();
+    let mut peeled: HashMap<String, String> = HashMap::new();
+    for r in connection.list()?.iter() {
+        if let Some(tag) = r.name().strip_suffix("^{}") {
+            // commit an annotated tag points to
+            peeled.insert(tag.to_string(), r.oid().to_string());
+        } else if let Some(git_ref) = GitRef::parse(r.name()) {
+            hashes.insert(git_ref, r.oid().to_string());
+        }
+    }
+    for (git_ref, hash) in hashes.iter_mut() {
+        if let Some(commit) = peeled.remove(&git_ref.refname) {
+            *hash = commit;
         }
     }
 
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use actix::prelude::*;
+
+use crate::{
+    git_utils::{
+        checkout_from_mirror, clone_commit, error_message, get_remote_refs, pull_lfs,
+        update_mirror, update_submodules, RemoteAccess,
+    },
+    models::{CheckoutMode, GitRef, Job, SubmoduleMode},
+};
+
+/// Number of threads running git operations. Polls and checkouts beyond this
+/// wait for a free thread, while the actors and the web server keep running.
+const GIT_WORKER_THREADS: usize = 4;
+
+/// Runs git operations which talk to remote repositories, and can take a long
+/// time, on a pool of threads. Results are sent back as messages.
+#[derive(Debug)]
+pub struct GitWorker {
+    mirror_locks: MirrorLocks,
+}
+
+impl GitWorker {
+    /// Start the pool of git worker threads
+    pub fn start_pool() -> Addr<GitWorker> {
+        let mirror_locks = MirrorLocks::default();
+        SyncArbiter::start(GIT_WORKER_THREADS, move || GitWorker {
+            mirror_locks: mirror_locks.clone(),
+        })
+    }
+
+    /// Check out a commit into a build's checkout directory, as configured by
+    /// the job. Returns the hash of the checked out commit.
+    fn checkout(&self, msg: &CheckoutMsg) -> Result<String, Box<dyn Error>> {
+        let remote = RemoteAccess::for_job(&msg.job)?;
+        match msg.job.checkout {
+            CheckoutMode::Mirror => {
+                // builds of other branches of the job share the mirror
+                let lock = self.mirror_locks.get(&msg.mirror_dir);
+                let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                update_mirror(&msg.job.repo_url, &msg.git_ref, &msg.mirror_dir, &remote)?;
+                checkout_from_mirror(
+                    &msg.job.repo_url,
+                    &msg.mirror_dir,
+                    &msg.git_ref,
+                    msg.commit_hash.clone(),
+                    &msg.checkout_dir,
+                    msg.job.clone_depth,
+                )
+            }
+            CheckoutMode::Clone => clone_commit(
+                &msg.job.repo_url,
+                &msg.git_ref,
+                msg.commit_hash.clone(),
+                &msg.checkout_dir,
+                &remote,
+            ),
+        }
+    }
+
+    /// Check out submodules and LFS files in a checkout, if the job wants them
+    fn prepare_checkout(&self, msg: &CheckoutMsg) -> Result<(), Box<dyn Error>> {
+        let remote = RemoteAccess::for_job(&msg.job)?;
+        match msg.job.submodules {
+            SubmoduleMode::None => {}
+            SubmoduleMode::Top => update_submodules(&msg.checkout_dir, false, &remote)?,
+            SubmoduleMode::Recursive => update_submodules(&msg.checkout_dir, true, &remote)?,
+        }
+        if msg.job.lfs {
+            pull_lfs(&msg.checkout_dir, &remote)?;
+        }
+        Ok(())
+    }
+}
+
+impl Actor for GitWorker {
+    type Context = SyncContext<Self>;
+}
+
+/// A lock for each mirror, so that only one thread at a time fetches into a
+/// mirror, or clones from it
+#[derive(Clone, Debug, Default)]
+struct MirrorLocks(Arc<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>>);
+
+impl MirrorLocks {
+    fn get(&self, mirror_dir: &Path) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(mirror_dir.to_path_buf()).or_default().clone()
+    }
+}
+
+/// List the refs of a job's repository. The refs, or the error, are sent to
+/// `reply` with a `RefsListedMsg`.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ListRefsMsg {
+    pub job: Job,
+    pub reply: Recipient<RefsListedMsg>,
+}
+
+/// Refs of a job's repository, with the commit each one points to
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct RefsListedMsg(pub Result<HashMap<GitRef, String>, String>);
+
+/// Check out a commit for a build, with submodules and LFS files if the job
+/// wants them. The outcome is sent to `reply` with a `CheckedOutMsg`.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CheckoutMsg {
+    pub job: Job,
+    pub git_ref: GitRef,
+    /// commit to check out, the head of the ref if not set
+    pub commit_hash: Option<String>,
+    pub checkout_dir: PathBuf,
+    /// the job's bare mirror, see `CheckoutMode::Mirror`
+    pub mirror_dir: PathBuf,
+    pub build_num: u64,
+    pub reply: Recipient<CheckedOutMsg>,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct CheckedOutMsg {
+    pub build_num: u64,
+    /// hash of the checked out commit, set even if submodules or LFS failed
+    pub commit_hash: Option<String>,
+    pub result: Result<(), String>,
+}
+
+impl Handler<ListRefsMsg> for GitWorker {
+    type Result = ();
+
+    fn handle(&mut self, msg: ListRefsMsg, _ctx: &mut Self::Context) -> Self::Result {
+        let refs = RemoteAccess::for_job(&msg.job)
+            .and_then(|remote| get_remote_refs(&msg.job.repo_url, &remote))
+            .map_err(|e| error_message(e.as_ref()));
+        // the job may have been removed in the meantime
+        let _ = msg.reply.do_send(RefsListedMsg(refs));
+    }
+}
+
+impl Handler<CheckoutMsg> for GitWorker {
+    type Result = ();
+
+    fn handle(&mut self, msg: CheckoutMsg, _ctx: &mut Self::Context) -> Self::Result {
+        let (commit_hash, result) = match self.checkout(&msg) {
+            Ok(hash) => (Some(hash), self.prepare_checkout(&msg)),
+            Err(err) => (None, Err(err)),
+        };
+        let _ = msg.reply.do_send(CheckedOutMsg {
+            build_num: msg.build_num,
+            commit_hash,
+            result: result.map_err(|e| error_message(e.as_ref())),
+        });
+    }
+}
@@ -0,0 +1,183 @@
+use globset::{Glob, GlobMatcher};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{convert::TryInto, path::Path};
+
+/// SSH host keys a job accepts for its remote, from a `known_hosts` file and
+/// pinned fingerprints
+#[derive(Debug, Default)]
+pub struct HostKeyVerifier {
+    entries: Vec<KnownHost>,
+    /// keys marked `@revoked` in the known_hosts file
+    revoked: Vec<[u8; 32]>,
+    fingerprints: Vec<[u8; 32]>,
+}
+
+/// A line of a known_hosts file
+#[derive(Debug)]
+struct KnownHost {
+    patterns: Vec<HostPattern>,
+    key_sha256: [u8; 32],
+}
+
+#[derive(Debug)]
+enum HostPattern {
+    /// `|1|salt|hash` entry written by `ssh-keygen -H`
+    Hashed {
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    Glob {
+        matcher: GlobMatcher,
+        negated: bool,
+    },
+}
+
+impl HostKeyVerifier {
+    pub fn new(known_hosts: Option<&str>, fingerprints: &[String]) -> Result<Self, String> {
+        let mut verifier = HostKeyVerifier {
+            fingerprints: fingerprints
+                .iter()
+                .map(|f| parse_fingerprint(f))
+                .collect::<Result<_, _>>()?,
+            ..Default::default()
+        };
+        if let Some(path) = known_hosts {
+            let contents = std::fs::read_to_string(Path::new(path))
+                .map_err(|e| format!("Could not read known_hosts file {:?}: {}", path, e))?;
+            for (i, line) in contents.lines().enumerate() {
+                verifier
+                    .add_known_hosts_line(line)
+                    .map_err(|e| format!("Line {} of {:?}: {}", i + 1, path, e))?;
+            }
+        }
+        Ok(verifier)
+    }
+
+    fn add_known_hosts_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let mut fields = line.split_whitespace();
+        let mut hosts = fields.next().unwrap_or_default();
+        let marker = if hosts.starts_with('@') {
+            let m = hosts;
+            hosts = fields.next().ok_or("Missing host names")?;
+            Some(m)
+        } else {
+            None
+        };
+        let _key_type = fields.next().ok_or("Missing key type")?;
+        let key = fields.next().ok_or("Missing key")?;
+        let key = base64::decode(key).map_err(|e| format!("Invalid key: {}", e))?;
+        let key_sha256: [u8; 32] = Sha256::digest(&key).into();
+        match marker {
+            Some("@revoked") => self.revoked.push(key_sha256),
+            // keys of certificate authorities can not be checked with the
+            // hash libgit2 provides
+            Some("@cert-authority") => {}
+            Some(m) => return Err(format!("Unknown marker {}", m)),
+            None => self.entries.push(KnownHost {
+                patterns: hosts
+                    .split(',')
+                    .map(HostPattern::new)
+                    .collect::<Result<_, _>>()?,
+                key_sha256,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Whether the host key with the given SHA256 hash is accepted for `host`
+    pub fn verify(&self, host: &str, key_sha256: &[u8; 32]) -> bool {
+        if self.revoked.contains(key_sha256) {
+            return false;
+        }
+        let host = host.to_lowercase();
+        self.fingerprints.contains(key_sha256)
+            || self
+                .entries
+                .iter()
+                .any(|e| &e.key_sha256 == key_sha256 && e.matches(&host))
+    }
+}
+
+impl KnownHost {
+    fn matches(&self, host: &str) -> bool {
+        let mut matched = false;
+        for p in &self.patterns {
+            match p {
+                HostPattern::Hashed { salt, hash } => {
+                    let mut mac = match Hmac::<Sha1>::new_from_slice(salt) {
+                        Ok(m) => m,
+                        Err(_) => continue,
+                    };
+                    mac.update(host.as_bytes());
+                    matched |= mac.verify_slice(hash).is_ok();
+                }
+                HostPattern::Glob { matcher, negated } => {
+                    if matcher.is_match(host) {
+                        if *negated {
+                            return false;
+                        }
+                        matched = true;
+                    }
+                }
+            }
+        }
+        matched
+    }
+}
+
+impl HostPattern {
+    fn new(pattern: &str) -> Result<Self, String> {
+        if let Some(hashed) = pattern.strip_prefix("|1|") {
+            let (salt, hash) = hashed.split_once('|').ok_or("Invalid hashed host name")?;
+            let decode = |s| base64::decode(s).map_err(|_| "Invalid hashed host name");
+            return Ok(HostPattern::Hashed {
+                salt: decode(salt)?,
+                hash: decode(hash)?,
+            });
+        }
+        let (pattern, negated) = match pattern.strip_prefix('!') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        // libgit2 only tells the host name, so entries for other ports match too
+        let pattern = match pattern.strip_prefix('[').and_then(|p| p.split_once("]:")) {
+            Some((host, _port)) => host,
+            None => pattern,
+        };
+        let matcher = Glob::new(&pattern.to_lowercase())
+            .map_err(|e| format!("Invalid host pattern {:?}: {}", pattern, e))?
+            .compile_matcher();
+        Ok(HostPattern::Glob { matcher, negated })
+    }
+}
+
+/// Parse a host key fingerprint as printed by OpenSSH, `SHA256:<base64>`
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], String> {
+    let invalid = || format!("Invalid host fingerprint {:?}", fingerprint);
+    let b64 = fingerprint
+        .trim()
+        .strip_prefix("SHA256:")
+        .ok_or_else(|| format!("{}, expected SHA256:<base64>", invalid()))?;
+    let bytes = base64::decode_config(b64.trim_end_matches('='), base64::STANDARD_NO_PAD)
+        .map_err(|_| invalid())?;
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// Whether a Git URL uses SSH, including scp-like `user@host:path` URLs
+pub fn is_ssh_url(url: &str) -> bool {
+    if let Some((scheme, _)) = url.split_once("://") {
+        return scheme == "ssh" || scheme == "git+ssh" || scheme == "ssh+git";
+    }
+    // scp-like syntax has a colon before the first slash
+    match (url.find(':'), url.find('/')) {
+        (Some(c), Some(s)) => c < s,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
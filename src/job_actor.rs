@@ -1,26 +1,101 @@
-use std::{collections::HashMap, fs::create_dir_all, path::PathBuf, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::create_dir_all,
+    path::PathBuf,
+    time::Duration,
+};
 
 use crate::{
-    branch_actor::{BranchActor, NewCommitMsg},
-    git_utils::get_branch_hashes,
+    branch_actor::{find_branches, AbortBuildsMsg, BranchActor, NewCommitMsg, SetArchivedMsg},
+    git_worker::{GitWorker, ListRefsMsg, RefsListedMsg},
     models::*,
+    persist::{backup_path, read_with_backup, write_atomic},
+    thingy::Thingy,
+    webhooks::{parse_push, verify_signature, WebhookSignature},
 };
 use actix::prelude::*;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Directory of the job's bare mirror of its repository, within the job's
+/// directory. Git does not allow ref names starting with `.`, so this never
+/// clashes with the directory of a branch.
+const MIRROR_DIR_NAME: &str = ".mirror";
+
+/// File with the job's `JobState`, within the job's directory. It starts with
+/// `.` for the same reason as `MIRROR_DIR_NAME`.
+const STATE_FILE_NAME: &str = ".state.json";
 
 #[derive(Debug)]
 pub struct JobActor {
     pub job: Job,
     pub dir: PathBuf,
+    /// address of the root actor, which owns the build queue
+    pub root: Addr<Thingy>,
+    /// runs git operations, off the actor's thread
+    git: Addr<GitWorker>,
     pub branch_actors: HashMap<String, Addr<BranchActor>>,
+    /// tracked refs which are no longer built, see `SetArchivedMsg`
+    archived: HashSet<String>,
+    /// branches of the repository which are built
+    branch_matcher: BranchMatcher,
+    state: JobState,
+    /// the next scheduled poll, replaced whenever the job is polled
+    next_poll: Option<SpawnHandle>,
+    /// whether the git workers are listing the refs of the repository
+    polling: bool,
+}
+
+/// State of a job which is kept across restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct JobState {
+    /// kinds of refs, other than branches, which the job built at the latest
+    /// successful poll
+    #[serde(default)]
+    built_ref_kinds: Vec<RefKind>,
+    /// tags and pull requests which already existed when the job started
+    /// building them, with their commits. They are not built, and are only
+    /// tracked once they get a new commit.
+    #[serde(default)]
+    existing_refs: HashMap<String, String>,
+    #[serde(default)]
+    poll_status: PollStatus,
+}
+
+/// Longest time between polls when polling keeps failing, unless the job's
+/// own poll interval is longer
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Outcome of the latest polls of a job's repository
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PollStatus {
+    last_poll: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+    /// number of failed polls since the last successful one
+    consecutive_failures: u32,
+    /// error of the latest failed poll, cleared when a poll succeeds
+    last_error: Option<String>,
 }
 
 impl JobActor {
-    pub fn new(job: Job, dir: PathBuf) -> Self {
+    pub fn new(job: Job, dir: PathBuf, root: Addr<Thingy>, git: Addr<GitWorker>) -> Self {
+        // the filter has been checked by `Job::validate`
+        let branch_matcher = job
+            .branches
+            .as_ref()
+            .and_then(|f| f.matcher().ok())
+            .unwrap_or_default();
         JobActor {
+            branch_matcher,
             job,
             dir,
+            root,
+            git,
             branch_actors: HashMap::new(),
+            archived: HashSet::new(),
+            state: JobState::default(),
+            next_poll: None,
+            polling: false,
         }
     }
 
@@ -28,6 +103,186 @@ impl JobActor {
     fn _poll(&mut self, context: &mut Context<Self>) {
         context.address().do_send(JobPollMsg);
     }
+
+    /// Time from one poll to the next, if the job polls. The job's poll
+    /// interval is doubled for every consecutive failed poll, up to
+    /// `MAX_POLL_BACKOFF`.
+    fn poll_delay(&self) -> Option<Duration> {
+        let interval = Duration::from_secs(self.job.poll_interval_seconds?);
+        let backoff = 2u32.saturating_pow(self.state.poll_status.consecutive_failures);
+        Some(
+            interval
+                .checked_mul(backoff)
+                .unwrap_or(Duration::MAX)
+                .min(MAX_POLL_BACKOFF.max(interval)),
+        )
+    }
+
+    /// Schedule the next poll after `poll_delay`
+    fn schedule_poll(&mut self, ctx: &mut Context<Self>) {
+        if let Some(h) = self.next_poll.take() {
+            ctx.cancel_future(h);
+        }
+        if let Some(delay) = self.poll_delay() {
+            self.next_poll = Some(ctx.run_later(delay, Self::_poll));
+        }
+    }
+
+    /// Poll when the job is started. If the polls of an earlier thingy
+    /// process were failing, the poll waits for the rest of their backoff.
+    fn schedule_first_poll(&mut self, ctx: &mut Context<Self>) {
+        let status = &self.state.poll_status;
+        let remaining = match (status.last_poll, self.poll_delay()) {
+            (Some(last_poll), Some(delay)) if status.consecutive_failures > 0 => {
+                let elapsed = (Utc::now() - last_poll).to_std().unwrap_or_default();
+                Some(delay.saturating_sub(elapsed))
+            }
+            _ => None,
+        };
+        match remaining {
+            Some(delay) if !delay.is_zero() => {
+                self.next_poll = Some(ctx.run_later(delay, Self::_poll));
+            }
+            _ => ctx.notify(JobPollMsg),
+        }
+    }
+
+    /// Start builds for new commits of the refs listed by a poll. Tags and
+    /// pull requests which exist when the job starts building them are
+    /// recorded in `JobState::existing_refs` instead of being built.
+    fn poll_refs(&mut self, mut hashes: HashMap<GitRef, String>) -> Result<(), std::io::Error> {
+        hashes.retain(|r, _| self.builds_ref(r));
+        let built_kinds: Vec<RefKind> = [RefKind::Tag, RefKind::PullRequest]
+            .iter()
+            .copied()
+            .filter(|k| self.builds_ref_kind(*k))
+            .collect();
+        for (r, v) in hashes.iter() {
+            let key = r.key();
+            if !self.branch_actors.contains_key(&key) {
+                if !self.state.built_ref_kinds.contains(&r.kind) && built_kinds.contains(&r.kind) {
+                    self.state.existing_refs.insert(key, v.clone());
+                    continue;
+                }
+                // tags which are moved are not rebuilt either
+                match self.state.existing_refs.get(&key) {
+                    Some(seen) if seen == v || r.kind == RefKind::Tag => continue,
+                    _ => {}
+                }
+            }
+            self.branch_actor(r)?
+                .do_send(NewCommitMsg(v.clone(), BuildTrigger::Poll));
+        }
+        // archive refs which are no longer present, or no longer built
+        let keys: HashSet<String> = hashes.keys().map(|r| r.key()).collect();
+        for (key, addr) in self.branch_actors.iter() {
+            if !keys.contains(key) && self.archived.insert(key.clone()) {
+                addr.do_send(SetArchivedMsg(true));
+            }
+        }
+        let branch_actors = &self.branch_actors;
+        self.state
+            .existing_refs
+            .retain(|k, _| keys.contains(k) && !branch_actors.contains_key(k));
+        self.state.built_ref_kinds = built_kinds;
+        Ok(())
+    }
+
+    fn get_state_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE_NAME)
+    }
+
+    /// Load the job's state saved by an earlier thingy process, if any
+    fn load_state(&mut self) {
+        let path = self.get_state_path();
+        if !path.exists() && !backup_path(&path).exists() {
+            return;
+        }
+        match read_with_backup(&path, |s| {
+            serde_json::from_str::<JobState>(s).map_err(|e| e.to_string())
+        }) {
+            Ok(state) => self.state = state,
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    fn save_state(&self) -> Result<(), std::io::Error> {
+        create_dir_all(&self.dir)?;
+        write_atomic(
+            &self.get_state_path(),
+            serde_json::to_string(&self.state)?.as_bytes(),
+        )
+    }
+
+    /// Start actors for the branches built by earlier thingy processes, so
+    /// that their history is available before the repository is polled
+    fn load_branches(&mut self) {
+        for branch in find_branches(&self.dir) {
+            let key = branch.git_ref.key();
+            if self.branch_actors.contains_key(&key) {
+                continue;
+            }
+            if branch.archived {
+                self.archived.insert(key.clone());
+            }
+            let addr = self.start_branch_actor(branch.git_ref, branch.dir, None);
+            self.branch_actors.insert(key, addr);
+        }
+    }
+
+    fn start_branch_actor(
+        &self,
+        git_ref: GitRef,
+        dir: PathBuf,
+        last_seen_commit: Option<String>,
+    ) -> Addr<BranchActor> {
+        BranchActor::new(
+            self.job.clone(),
+            git_ref,
+            dir,
+            self.dir.join(MIRROR_DIR_NAME),
+            self.root.clone(),
+            self.git.clone(),
+            last_seen_commit,
+        )
+        .start()
+    }
+
+    /// Whether the job builds a ref, according to its configuration
+    fn builds_ref(&self, git_ref: &GitRef) -> bool {
+        match git_ref.kind {
+            RefKind::Branch => self.branch_matcher.is_match(git_ref.short_name()),
+            kind => self.builds_ref_kind(kind),
+        }
+    }
+
+    /// Whether the job builds refs of a kind. Only some branches may be built.
+    fn builds_ref_kind(&self, kind: RefKind) -> bool {
+        match kind {
+            RefKind::Branch => true,
+            RefKind::Tag => self.job.build_tags,
+            RefKind::PullRequest => self.job.build_pull_requests,
+        }
+    }
+
+    /// Get the actor of a ref which exists in the repository, starting one
+    /// if the ref is new, and bringing it back if it was archived
+    fn branch_actor(&mut self, git_ref: &GitRef) -> Result<Addr<BranchActor>, std::io::Error> {
+        let key = git_ref.key();
+        if let Some(a) = self.branch_actors.get(&key) {
+            if self.archived.remove(&key) {
+                a.do_send(SetArchivedMsg(false));
+            }
+            return Ok(a.clone());
+        }
+        // ensure dir
+        let bpath = self.dir.join(&key);
+        create_dir_all(&bpath)?;
+        let seen = self.state.existing_refs.remove(&key);
+        let h = self.start_branch_actor(git_ref.clone(), bpath, seen);
+        self.branch_actors.insert(key, h.clone());
+        Ok(h)
+    }
 }
 
 #[derive(Message, Debug)]
@@ -41,21 +296,59 @@ pub struct GetJobDetailsMsg;
 #[derive(Debug, Serialize)]
 pub struct JobDetailsResponse {
     name: String,
+    /// names of all tracked refs, including tags and pull requests
     branches: Vec<String>,
+    refs: Vec<RefDetails>,
+    poll_status: PollStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefDetails {
+    /// name under which the ref is tracked, as in `branches`
+    name: String,
+    kind: RefKind,
+    refname: String,
+    /// whether the ref is no longer built, see `SetArchivedMsg`
+    archived: bool,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "Result<Option<Addr<BranchActor>>, std::io::Error>")]
 pub struct GetBranchActorMsg(pub String);
 
+/// A webhook request for this job
+#[derive(Message, Debug)]
+#[rtype(result = "Result<WebhookResult, std::io::Error>")]
+pub struct WebhookMsg {
+    pub signature: WebhookSignature,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum WebhookResult {
+    /// A build of the pushed commit will be considered
+    Accepted,
+    /// The request was valid, but is not a push to a branch
+    Ignored,
+    /// The job does not have webhooks configured
+    NotEnabled,
+    Unauthorized(String),
+    BadRequest(String),
+}
+
+/// Abort all builds of this job and stop, sent when the job is removed
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), std::io::Error>")]
+pub struct StopJobMsg;
+
 impl Actor for JobActor {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Context<Self>) {
-        if let Some(i) = self.job.poll_interval_seconds {
-            _ctx.run_interval(Duration::from_secs(i), Self::_poll);
-        }
-        _ctx.notify(JobPollMsg);
+        self.load_state();
+        self.load_branches();
+        // polling reschedules itself
+        self.schedule_first_poll(_ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {}
@@ -64,35 +357,48 @@ impl Actor for JobActor {
 impl Handler<JobPollMsg> for JobActor {
     type Result = Result<(), std::io::Error>;
 
-    fn handle(&mut self, _msg: JobPollMsg, _ctx: &mut Self::Context) -> Self::Result {
-        if let Ok(hashes) = get_branch_hashes(&self.job.repo_url, self.job.auth.as_ref()) {
-            for (k, v) in hashes.iter() {
-                match self.branch_actors.get(k) {
-                    Some(a) => {
-                        a.do_send(NewCommitMsg(v.clone()));
-                    }
-                    _ => {
-                        // ensure dir
-                        let bpath = self.dir.join(k);
-                        create_dir_all(&bpath)?;
-                        let h =
-                            BranchActor::new(self.job.clone(), k.clone(), bpath, None).start();
-                        self.branch_actors.insert(k.clone(), h);
-                        self.branch_actors
-                            .get(k)
-                            .unwrap()
-                            .do_send(NewCommitMsg(v.clone()));
-                    }
-                }
+    fn handle(&mut self, _msg: JobPollMsg, ctx: &mut Self::Context) -> Self::Result {
+        // the poll in progress schedules the next one when it is done
+        if self.polling {
+            return Ok(());
+        }
+        if let Some(h) = self.next_poll.take() {
+            ctx.cancel_future(h);
+        }
+        self.polling = true;
+        self.state.poll_status.last_poll = Some(Utc::now());
+        self.git.do_send(ListRefsMsg {
+            job: self.job.clone(),
+            reply: ctx.address().recipient(),
+        });
+        Ok(())
+    }
+}
+
+impl Handler<RefsListedMsg> for JobActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, msg: RefsListedMsg, ctx: &mut Self::Context) -> Self::Result {
+        self.polling = false;
+        let result = msg
+            .0
+            .and_then(|hashes| self.poll_refs(hashes).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                self.state.poll_status.last_success = Some(Utc::now());
+                self.state.poll_status.consecutive_failures = 0;
+                self.state.poll_status.last_error = None;
+            }
+            Err(error) => {
+                eprintln!("Could not poll job {}: {}", self.job.name, error);
+                self.state.poll_status.consecutive_failures += 1;
+                self.state.poll_status.last_error = Some(error);
             }
-            // remove branches which are no longer present
-            self.branch_actors = self
-                .branch_actors
-                .clone()
-                .into_iter()
-                .filter(|(k, _)| hashes.contains_key(k))
-                .collect();
         }
+        if let Err(err) = self.save_state() {
+            eprintln!("Could not save the state of job {}: {}", self.job.name, err);
+        }
+        self.schedule_poll(ctx);
         Ok(())
     }
 }
@@ -101,18 +407,69 @@ impl Handler<GetJobDetailsMsg> for JobActor {
     type Result = Result<JobDetailsResponse, std::io::Error>;
 
     fn handle(&mut self, _msg: GetJobDetailsMsg, _ctx: &mut Self::Context) -> Self::Result {
-        let branches: Vec<String> = self.branch_actors.iter().map(|(k, _)| k.clone()).collect();
+        let branches: Vec<String> = self.branch_actors.keys().cloned().collect();
+        let refs = branches
+            .iter()
+            .map(|k| {
+                let r = GitRef::from_key(k);
+                RefDetails {
+                    name: k.clone(),
+                    kind: r.kind,
+                    refname: r.refname,
+                    archived: self.archived.contains(k),
+                }
+            })
+            .collect();
         Ok(JobDetailsResponse {
             name: self.job.name.clone(),
             branches,
+            refs,
+            poll_status: self.state.poll_status.clone(),
         })
     }
 }
 
+impl Handler<WebhookMsg> for JobActor {
+    type Result = Result<WebhookResult, std::io::Error>;
+
+    fn handle(&mut self, msg: WebhookMsg, _ctx: &mut Self::Context) -> Self::Result {
+        let config = match &self.job.webhook {
+            Some(c) => c,
+            None => return Ok(WebhookResult::NotEnabled),
+        };
+        if let Err(err) = verify_signature(&msg.signature, &msg.body, config.secret.value()) {
+            return Ok(WebhookResult::Unauthorized(err));
+        }
+        let push = match parse_push(&msg.body) {
+            Ok(Some(p)) => p,
+            Ok(None) => return Ok(WebhookResult::Ignored),
+            Err(err) => return Ok(WebhookResult::BadRequest(err)),
+        };
+        if !self.builds_ref(&push.git_ref) {
+            return Ok(WebhookResult::Ignored);
+        }
+        self.branch_actor(&push.git_ref)?
+            .do_send(NewCommitMsg(push.commit_hash, BuildTrigger::Webhook));
+        Ok(WebhookResult::Accepted)
+    }
+}
+
+impl Handler<StopJobMsg> for JobActor {
+    type Result = Result<(), std::io::Error>;
+
+    fn handle(&mut self, _msg: StopJobMsg, ctx: &mut Self::Context) -> Self::Result {
+        for a in self.branch_actors.values() {
+            a.do_send(AbortBuildsMsg("job was removed".into()));
+        }
+        ctx.stop();
+        Ok(())
+    }
+}
+
 impl Handler<GetBranchActorMsg> for JobActor {
     type Result = Result<Option<Addr<BranchActor>>, std::io::Error>;
 
     fn handle(&mut self, msg: GetBranchActorMsg, _ctx: &mut Self::Context) -> Self::Result {
-        Ok(self.branch_actors.get(&msg.0).map(|a| a.clone()))
+        Ok(self.branch_actors.get(&msg.0).cloned())
     }
 }
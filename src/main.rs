@@ -1,10 +1,9 @@
 #![forbid(unsafe_code)]
-use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use std::{collections::HashMap, fmt::Display, path::PathBuf, sync::Arc};
 
 use actix::prelude::*;
-use branch_actor::{BuildNowMsg, GetBranchDetailsMsg, GetBuildActorMsg, GetBuildLogLinesMsg};
-use build_actor::StopBuildMessage;
-use job_actor::{GetBranchActorMsg, GetJobDetailsMsg, JobPollMsg};
+use branch_actor::{AbortBuildMsg, BuildNowMsg, GetBranchDetailsMsg, GetBuildLogLinesMsg};
+use job_actor::{GetBranchActorMsg, GetJobDetailsMsg, JobPollMsg, WebhookMsg, WebhookResult};
 use thingy::{
     AddJobMsg, GetJobActorMsg, GetJobActorResponse, GetJobsMsg, RemoveJobMsg, Thingy,
 };
@@ -15,29 +14,57 @@ use actix_web::{
     dev::HttpResponseBuilder,
     get,
     http::{header, StatusCode},
-    post, web, App, HttpResponse, HttpServer, Responder,
+    post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
+use api_auth::{AuthError, Principal};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+mod api_auth;
 mod branch_actor;
 mod build_actor;
+mod git_auth;
 mod git_utils;
+mod git_worker;
+mod host_keys;
 mod job_actor;
 mod models;
+mod persist;
+mod secrets;
 mod thingy;
+mod tls;
+mod webhooks;
 
 use models::*;
+use secrets::SecretStore;
+use tls::ReloadingCertResolver;
+use webhooks::WebhookSignature;
+
+/// Maximum size of webhook request bodies, push payloads can be large
+const MAX_WEBHOOK_PAYLOAD_BYTES: usize = 10 * 1024 * 1024;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let mut args = std::env::args();
     if args.len() < 2 {
         eprintln!("Usage: thingy <path to workspace dir>");
+        eprintln!("{}", secrets::SECRETS_USAGE);
         return Ok(());
     }
     args.next();
     let path = args.next();
+    if path.as_deref() == Some("secrets") {
+        let args: Vec<String> = args.collect();
+        let result = match args.split_first() {
+            Some((dir, cmd)) => secrets::run_command(&PathBuf::from(dir), cmd),
+            None => Err(secrets::SECRETS_USAGE.into()),
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     if path.is_none() {
         eprintln!("Usage: thingy <path to workspace dir>");
         return Ok(());
@@ -45,9 +72,31 @@ async fn main() -> std::io::Result<()> {
     let path = path.unwrap();
 
     let path = PathBuf::from(path).canonicalize()?;
-    let ws = Workspace::from_dir_path(&path).unwrap();
+    let secrets = match SecretStore::open(&path) {
+        Ok(s) => s,
+        Err(err) => {
+            eprintln!("{}. Exiting.", err);
+            std::process::exit(1);
+        }
+    };
+    let tls = match ReloadingCertResolver::from_env() {
+        Ok(r) => r.map(Arc::new),
+        Err(err) => {
+            eprintln!("{}. Exiting.", err);
+            std::process::exit(1);
+        }
+    };
+    let ws = match Workspace::from_dir_path(&path, &secrets) {
+        Ok(ws) => ws,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let api_auth = ws.api_auth.clone().map(Arc::new);
     let state = ThingyState {
-        root: Thingy::new(ws, path.into()).start(),
+        root: Thingy::new(ws, path, secrets).start(),
+        api_auth: api_auth.clone(),
     };
 
     let listen_addr: String = if let Ok(addr) = std::env::var("LISTEN_ADDRESS") {
@@ -60,10 +109,20 @@ async fn main() -> std::io::Result<()> {
     } else {
         8080
     };
-    HttpServer::new(move || {
+    if api_auth.is_none() && !is_loopback(&listen_addr) {
+        eprintln!(
+            "Warning: the API is open to everyone who can reach {}, configure api_auth in thingy.yaml to restrict it",
+            listen_addr
+        );
+    }
+    let server = HttpServer::new(move || {
+        let api_auth = state.api_auth.clone();
         let mut app = App::new()
             .data(state.clone())
+            .app_data(web::PayloadConfig::new(MAX_WEBHOOK_PAYLOAD_BYTES))
+            .wrap_fn(move |req, srv| api_auth::check_credentials(api_auth.as_deref(), req, srv))
             .service(index)
+            .service(whoami)
             .service(get_jobs)
             .service(create_job)
             .service(delete_job)
@@ -72,15 +131,38 @@ async fn main() -> std::io::Result<()> {
             .service(get_branch)
             .service(force_build)
             .service(get_build_log)
-            .service(abort_build);
-        if let Ok(_) = std::env::var("SERVE_STATIC") {
+            .service(abort_build)
+            .service(webhook);
+        if std::env::var("SERVE_STATIC").is_ok() {
             app = app.service(fs::Files::new("/", "./static/").show_files_listing());
         }
         app
-    })
-    .bind((listen_addr, port))?
-    .run()
-    .await?;
+    });
+    let mut redirect_server = None;
+    let server = match tls {
+        Some(resolver) => {
+            resolver.clone().reload_on_sighup()?;
+            if let Ok(Ok(http_port)) =
+                std::env::var("HTTP_REDIRECT_PORT").map(|pstr| pstr.parse::<u16>())
+            {
+                redirect_server = Some(
+                    HttpServer::new(move || {
+                        App::new()
+                            .data(port)
+                            .default_service(web::route().to(tls::redirect_to_https))
+                    })
+                    .bind((listen_addr.clone(), http_port))?
+                    .run(),
+                );
+            }
+            server.bind_rustls((listen_addr, port), resolver.server_config())?
+        }
+        None => server.bind((listen_addr, port))?,
+    };
+    server.run().await?;
+    if let Some(redirect_server) = redirect_server {
+        redirect_server.stop(true).await;
+    }
     println!("shutting down...");
     Ok(())
 }
@@ -90,6 +172,16 @@ async fn main() -> std::io::Result<()> {
 struct ThingyState {
     /// address of the root actor
     root: Addr<Thingy>,
+    /// who may use the API, everyone if not set
+    api_auth: Option<Arc<ApiAuth>>,
+}
+
+fn is_loopback(addr: &str) -> bool {
+    addr == "localhost"
+        || addr
+            .parse::<std::net::IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
 }
 
 /// Contents of static/index.html , served at GET /
@@ -146,6 +238,20 @@ impl From<MailboxError> for ApiMessage {
     }
 }
 
+/// Convert refused API requests to ApiMessage
+impl From<AuthError> for ApiMessage {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Unauthorized => {
+                ApiMessage::new_with_status(StatusCode::UNAUTHORIZED, "Authentication required")
+            }
+            AuthError::Forbidden => {
+                ApiMessage::new_with_status(StatusCode::FORBIDDEN, "Not allowed")
+            }
+        }
+    }
+}
+
 /// Convert I/O error to ApiMessage
 impl From<std::io::Error> for ApiMessage {
     // TODO: get description from io error
@@ -163,6 +269,34 @@ async fn index() -> impl Responder {
         .body(HTML_BYTES)
 }
 
+#[derive(Serialize)]
+struct WhoamiResponse {
+    auth_enabled: bool,
+    /// role of the request's credentials. Everyone is an admin if auth is
+    /// not enabled.
+    role: Option<Role>,
+    name: Option<String>,
+}
+
+/// Who the request's `Authorization` header belongs to, used by the web UI
+/// to check logins
+#[get("/whoami")]
+async fn whoami(req: HttpRequest, data: web::Data<ThingyState>) -> impl Responder {
+    let principal = req.extensions().get::<Principal>().cloned();
+    HttpResponse::Ok().json(match (&data.api_auth, principal) {
+        (None, _) => WhoamiResponse {
+            auth_enabled: false,
+            role: Some(Role::Admin),
+            name: None,
+        },
+        (Some(_), p) => WhoamiResponse {
+            auth_enabled: true,
+            role: p.as_ref().map(|p| p.role),
+            name: p.map(|p| p.name),
+        },
+    })
+}
+
 /// List jobs
 #[get("/jobs")]
 async fn get_jobs(data: web::Data<ThingyState>) -> Result<HttpResponse, ApiMessage> {
@@ -180,7 +314,7 @@ async fn create_job(
 }
 
 /// Remove a job from workspace, this updates the <workspace>/thingy.yaml file
-/// Any ongoing builds related to thsi job will not be stopped immediately
+/// Any ongoing builds related to this job are aborted
 #[delete("/jobs/{jobId}")]
 async fn delete_job(
     path: web::Path<(String,)>,
@@ -306,8 +440,13 @@ async fn force_build(
     let branch = path.1;
     if let GetJobActorResponse(Some(addr)) = data.root.send(GetJobActorMsg(job_id)).await?? {
         if let Some(addr) = addr.send(GetBranchActorMsg(branch)).await?? {
-            addr.do_send(BuildNowMsg);
-            return Err(ApiMessage::new_with_status(StatusCode::OK, "OK"));
+            if addr.send(BuildNowMsg).await?? {
+                return Err(ApiMessage::new_with_status(StatusCode::OK, "OK"));
+            }
+            return Err(ApiMessage::new_with_status(
+                StatusCode::CONFLICT,
+                "Branch is archived",
+            ));
         }
     }
     Err(ApiMessage::new_with_status(
@@ -327,8 +466,11 @@ async fn abort_build(
     let build_num = path.2;
     if let GetJobActorResponse(Some(addr)) = data.root.send(GetJobActorMsg(job_id)).await?? {
         if let Some(addr) = addr.send(GetBranchActorMsg(branch)).await?? {
-            if let Some(addr) = addr.send(GetBuildActorMsg(build_num)).await?? {
-                addr.do_send(StopBuildMessage);
+            let msg = AbortBuildMsg {
+                build_num,
+                reason: "aborted by user".into(),
+            };
+            if addr.send(msg).await?? {
                 return Err(ApiMessage::new_with_status(StatusCode::OK, "OK"));
             }
         }
@@ -338,3 +480,57 @@ async fn abort_build(
         "Not found",
     ))
 }
+
+/// Receive a push webhook from Github, Gitea, Forgejo or Gitlab. The job must
+/// have a webhook secret configured, which is used to verify the request.
+#[post("/hooks/{jobId}")]
+async fn webhook(
+    path: web::Path<(String,)>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<ThingyState>,
+) -> Result<HttpResponse, ApiMessage> {
+    let id = path.into_inner().0;
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let signature = WebhookSignature {
+        github: header("X-Hub-Signature-256"),
+        gitea: header("X-Gitea-Signature").or_else(|| header("X-Forgejo-Signature")),
+        gitlab_token: header("X-Gitlab-Token"),
+    };
+    if let GetJobActorResponse(Some(addr)) = data.root.send(GetJobActorMsg(id)).await?? {
+        let msg = WebhookMsg {
+            signature,
+            body: body.to_vec(),
+        };
+        match addr.send(msg).await?? {
+            WebhookResult::Accepted => {
+                return Err(ApiMessage::new_with_status(StatusCode::OK, "OK"));
+            }
+            WebhookResult::Ignored => {
+                return Err(ApiMessage::new_with_status(StatusCode::OK, "Ignored"));
+            }
+            WebhookResult::Unauthorized(message) => {
+                return Err(ApiMessage::new_with_status(
+                    StatusCode::UNAUTHORIZED,
+                    &message,
+                ));
+            }
+            WebhookResult::BadRequest(message) => {
+                return Err(ApiMessage::new_with_status(
+                    StatusCode::BAD_REQUEST,
+                    &message,
+                ));
+            }
+            WebhookResult::NotEnabled => {}
+        }
+    }
+    Err(ApiMessage::new_with_status(
+        StatusCode::NOT_FOUND,
+        "Not found",
+    ))
+}
@@ -1,25 +1,51 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    host_keys::{is_ssh_url, HostKeyVerifier},
+    persist::{backup_path, write_atomic},
+    secrets::SecretStore,
+};
+
 /// A workspace containing build jobs
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Workspace {
     pub jobs: Vec<Job>,
+    /// Maximum number of builds running at the same time across all jobs.
+    /// Further builds wait in a queue. Unlimited if not set.
+    pub max_concurrent_builds: Option<usize>,
+    /// Who may use the REST API and web UI. Everyone may if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_auth: Option<ApiAuth>,
 }
 
 impl Workspace {
     pub fn from_dir_path(
         path: &PathBuf,
+        secrets: &SecretStore,
     ) -> std::result::Result<Workspace, Box<dyn std::error::Error>> {
         println!("Initing thingy in workspace {:?}", &path);
 
         let ws_yaml_path = path.clone().join("thingy.yaml");
 
         if !ws_yaml_path.exists() {
-            std::fs::write(&ws_yaml_path, serde_yaml::to_string(&Workspace {
-                jobs: vec![],
-            })?)?;
+            write_atomic(
+                &ws_yaml_path,
+                serde_yaml::to_string(&Workspace {
+                    jobs: vec![],
+                    max_concurrent_builds: None,
+                    api_auth: None,
+                })?
+                .as_bytes(),
+            )?;
         }
 
         let md = std::fs::metadata(&ws_yaml_path);
@@ -46,14 +72,29 @@ impl Workspace {
         let ws = serde_yaml::from_str::<Workspace>(&contents);
 
         if let Err(err) = &ws {
-            return Err(format!(
+            let mut message = format!(
                 "Could not read {:?}. Exiting. Does the file contain valid YAML? Error: {:?}",
                 &ws_yaml_path, &err
-            )
-            .into());
+            );
+            let backup = backup_path(&ws_yaml_path);
+            if backup.exists() {
+                message += &format!(
+                    "\nThe version before thingy last changed it is in {:?}",
+                    backup
+                );
+            }
+            return Err(message.into());
         }
 
         let mut ws = ws.unwrap();
+        if ws.max_concurrent_builds.eq(&Some(0)) {
+            return Err("max_concurrent_builds must be > 0. Exiting.".into());
+        }
+        if let Some(auth) = &mut ws.api_auth {
+            if let Err(err) = auth.resolve_secrets(secrets).and_then(|_| auth.validate()) {
+                return Err(format!("API auth configuration is invalid: {}. Exiting.", err).into());
+            }
+        }
         let names: Vec<&str> = ws.jobs.iter().map(|j| j.name.trim()).collect();
 
         let mut uniq = HashSet::<&str>::new();
@@ -68,7 +109,7 @@ impl Workspace {
         }
 
         for j in &mut ws.jobs {
-            if let Err(err) = &j.validate() {
+            if let Err(err) = &j.resolve_secrets(secrets).and_then(|_| j.validate()) {
                 return Err(
                     format!("Configuration for {} is invalid: {}. Exiting.", j.name, err).into(),
                 );
@@ -99,6 +140,88 @@ impl Workspace {
     }
 }
 
+/// Users and tokens which may use the REST API
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ApiAuth {
+    /// Tokens sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    /// Users logging in with HTTP basic auth
+    #[serde(default)]
+    pub users: Vec<ApiUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    /// Name of the token, for telling tokens apart
+    pub name: String,
+    pub token: SecretValue,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiUser {
+    pub username: String,
+    pub password: SecretValue,
+    pub role: Role,
+}
+
+/// What a user or token may do with the API. Each role may do everything
+/// the roles before it may.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// See jobs, branches, builds and logs
+    Viewer,
+    /// Also poll jobs, and start and abort builds
+    Operator,
+    /// Also add and remove jobs
+    Admin,
+}
+
+/// Tokens shorter than this are refused, they could be guessed
+const MIN_API_TOKEN_LEN: usize = 16;
+
+impl ApiAuth {
+    fn resolve_secrets(&mut self, store: &SecretStore) -> Result<(), String> {
+        for t in &mut self.tokens {
+            t.token.resolve(store)?;
+        }
+        for u in &mut self.users {
+            u.password.resolve(store)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for t in &self.tokens {
+            if !names.insert(t.name.as_str()) {
+                return Err(format!("Duplicate token name {:?}", t.name));
+            }
+            if t.token.value().len() < MIN_API_TOKEN_LEN {
+                return Err(format!(
+                    "Token {:?} is shorter than {} characters",
+                    t.name, MIN_API_TOKEN_LEN
+                ));
+            }
+        }
+        let mut usernames = HashSet::new();
+        for u in &self.users {
+            if u.username.is_empty() || u.username.contains(':') {
+                return Err(format!("Invalid username {:?}", u.username));
+            }
+            if !usernames.insert(u.username.as_str()) {
+                return Err(format!("Duplicate user {:?}", u.username));
+            }
+            if u.password.value().is_empty() {
+                return Err(format!("Password of user {:?} is empty", u.username));
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A build job
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Job {
@@ -112,9 +235,368 @@ pub struct Job {
     pub poll_interval_seconds: Option<u64>,
     /// Authentication for Git fetch, if required
     pub auth: Option<GitAuth>,
+    /// Additional environment variables for build scripts, values may be
+    /// references to secrets
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, SecretValue>,
+    /// Absolute path to a file with `KEY=VALUE` lines, read at the start of
+    /// each build. Variables in `env` take precedence over ones in this file.
+    pub env_file: Option<String>,
+    /// Start build scripts with a minimal environment, instead of passing on
+    /// thingy's own environment variables
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Maximum duration of a build in seconds, after which it is terminated
+    pub timeout_seconds: Option<u64>,
+    /// Seconds to wait after asking a build to terminate before killing it,
+    /// defaults to 10 seconds
+    pub kill_grace_seconds: Option<u64>,
+    /// Maximum number of builds of this job running at the same time.
+    /// Unlimited if not set, but the workspace wide limit still applies.
+    pub max_concurrent_builds: Option<usize>,
+    /// What to do when a new commit is found while a build of the same
+    /// branch is queued or running
+    #[serde(default)]
+    pub on_new_commit: NewCommitPolicy,
+    /// What happens to builds which were queued or running when thingy
+    /// stopped, once it starts again
+    #[serde(default)]
+    pub resume_on_restart: ResumePolicy,
+    /// Accept push webhooks at `POST /hooks/<job name>`
+    pub webhook: Option<WebhookConfig>,
+    /// Which branches to build. All branches are built if not set.
+    pub branches: Option<BranchFilter>,
+    /// Build each new tag once
+    #[serde(default)]
+    pub build_tags: bool,
+    /// Build pull requests (`refs/pull/*/head`) and merge requests
+    /// (`refs/merge-requests/*/head`)
+    #[serde(default)]
+    pub build_pull_requests: bool,
+    /// How the repository is checked out for builds
+    #[serde(default)]
+    pub checkout: CheckoutMode,
+    /// Number of commits of history in checkouts made from the mirror. Full
+    /// history if not set.
+    pub clone_depth: Option<u32>,
+    /// Which submodules of the repository to check out
+    #[serde(default)]
+    pub submodules: SubmoduleMode,
+    /// Fetch Git LFS files in checkouts, needs the `git-lfs` command line tool
+    #[serde(default)]
+    pub lfs: bool,
+    /// Path to a file in OpenSSH `known_hosts` format with the accepted SSH
+    /// host keys of the repository's host
+    pub known_hosts: Option<String>,
+    /// SHA256 fingerprints of accepted SSH host keys, like `SHA256:<base64>`
+    /// as printed by `ssh-keygen -l`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_fingerprints: Vec<String>,
+}
+
+/// What happens to builds which were interrupted by thingy stopping
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumePolicy {
+    /// Interrupted builds are not built again
+    #[default]
+    Never,
+    /// Interrupted builds are queued again, as new builds of the same commit.
+    /// Only the latest one of a branch is, unless the job builds new commits
+    /// in parallel.
+    Rebuild,
+}
+
+/// Which submodules are checked out for builds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmoduleMode {
+    #[default]
+    None,
+    /// Submodules of the repository, but not their own submodules
+    Top,
+    Recursive,
+}
+
+/// How builds get a checkout of the repository
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckoutMode {
+    /// Fetch new commits into a bare mirror kept in the job's directory, and
+    /// make a local clone of the mirror for each build
+    #[default]
+    Mirror,
+    /// Clone the repository from its remote for each build
+    Clone,
+}
+
+/// Include and exclude patterns for branch names. Patterns are globs, like
+/// `release/*`, or regular expressions when prefixed with `regex:`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BranchFilter {
+    /// Branches matching any of these are built. All branches are included
+    /// if this is empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Branches matching any of these are not built, even if included
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl BranchFilter {
+    pub fn matcher(&self) -> Result<BranchMatcher, String> {
+        let compile = |patterns: &Vec<String>| -> Result<Vec<BranchPattern>, String> {
+            patterns.iter().map(|p| BranchPattern::new(p)).collect()
+        };
+        Ok(BranchMatcher {
+            include: compile(&self.include)?,
+            exclude: compile(&self.exclude)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum BranchPattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl BranchPattern {
+    fn new(pattern: &str) -> Result<Self, String> {
+        match pattern.strip_prefix("regex:") {
+            Some(re) => Regex::new(re)
+                .map(BranchPattern::Regex)
+                .map_err(|e| format!("Invalid branch regex {:?}: {}", re, e)),
+            None => Glob::new(pattern)
+                .map(|g| BranchPattern::Glob(g.compile_matcher()))
+                .map_err(|e| format!("Invalid branch pattern {:?}: {}", pattern, e)),
+        }
+    }
+
+    fn is_match(&self, branch: &str) -> bool {
+        match self {
+            BranchPattern::Glob(g) => g.is_match(branch),
+            BranchPattern::Regex(r) => r.is_match(branch),
+        }
+    }
+}
+
+/// Compiled form of a `BranchFilter`
+#[derive(Debug, Default)]
+pub struct BranchMatcher {
+    include: Vec<BranchPattern>,
+    exclude: Vec<BranchPattern>,
+}
+
+impl BranchMatcher {
+    pub fn is_match(&self, branch: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.is_match(branch)))
+            && !self.exclude.iter().any(|p| p.is_match(branch))
+    }
+}
+
+/// Kind of a ref which thingy builds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RefKind {
+    #[default]
+    Branch,
+    Tag,
+    /// Github/Gitea pull request, or Gitlab merge request
+    PullRequest,
+}
+
+impl RefKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefKind::Branch => "branch",
+            RefKind::Tag => "tag",
+            RefKind::PullRequest => "pull_request",
+        }
+    }
+}
+
+/// A ref of a job's repository which can be built
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GitRef {
+    pub kind: RefKind,
+    /// full name of the ref, like `refs/heads/main` or `refs/pull/12/head`
+    pub refname: String,
+}
+
+impl GitRef {
+    pub fn branch(name: &str) -> Self {
+        GitRef {
+            kind: RefKind::Branch,
+            refname: format!("refs/heads/{}", name),
+        }
+    }
+
+    /// Get the ref for a full ref name, if it is of a kind thingy can build
+    pub fn parse(refname: &str) -> Option<Self> {
+        let kind = if refname.starts_with("refs/heads/") {
+            RefKind::Branch
+        } else if refname.starts_with("refs/tags/") && !refname.ends_with("^{}") {
+            RefKind::Tag
+        } else if pull_request_number(refname).is_some() {
+            RefKind::PullRequest
+        } else {
+            return None;
+        };
+        let r = GitRef {
+            kind,
+            refname: refname.to_string(),
+        };
+        if r.short_name().is_empty() {
+            return None;
+        }
+        Some(r)
+    }
+
+    /// Branch or tag name, or pull request number
+    pub fn short_name(&self) -> &str {
+        match self.kind {
+            RefKind::Branch => self.refname.strip_prefix("refs/heads/").unwrap_or_default(),
+            RefKind::Tag => self.refname.strip_prefix("refs/tags/").unwrap_or_default(),
+            RefKind::PullRequest => pull_request_number(&self.refname).unwrap_or_default(),
+        }
+    }
+
+    /// Name under which thingy tracks this ref, in the API and in the job's
+    /// directory. This is the branch name for branches, and `tag:<name>`,
+    /// `pull:<number>` or `merge-request:<number>` for other refs. Git does
+    /// not allow `:` in ref names, so these never clash with branch names.
+    pub fn key(&self) -> String {
+        match self.kind {
+            RefKind::Branch => self.short_name().to_string(),
+            RefKind::Tag => format!("tag:{}", self.short_name()),
+            RefKind::PullRequest if self.refname.starts_with("refs/pull/") => {
+                format!("pull:{}", self.short_name())
+            }
+            RefKind::PullRequest => format!("merge-request:{}", self.short_name()),
+        }
+    }
+
+    /// Get the ref tracked under a name returned by `key`
+    pub fn from_key(key: &str) -> Self {
+        let refname = if let Some(t) = key.strip_prefix("tag:") {
+            format!("refs/tags/{}", t)
+        } else if let Some(n) = key.strip_prefix("pull:") {
+            format!("refs/pull/{}/head", n)
+        } else if let Some(n) = key.strip_prefix("merge-request:") {
+            format!("refs/merge-requests/{}/head", n)
+        } else {
+            return GitRef::branch(key);
+        };
+        GitRef::parse(&refname).unwrap_or_else(|| GitRef::branch(key))
+    }
+}
+
+/// Number of a `refs/pull/<n>/head` or `refs/merge-requests/<n>/head` ref
+fn pull_request_number(refname: &str) -> Option<&str> {
+    refname
+        .strip_prefix("refs/pull/")
+        .or_else(|| refname.strip_prefix("refs/merge-requests/"))?
+        .strip_suffix("/head")
+        .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Webhook settings of a job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    /// Secret used to sign webhook requests (Github, Gitea, Forgejo), or sent
+    /// along as a token (Gitlab)
+    pub secret: SecretValue,
+}
+
+/// How a branch handles new commits while one of its builds is in flight
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NewCommitPolicy {
+    /// Build every commit, in parallel with builds in flight
+    #[default]
+    Parallel,
+    /// Build the latest new commit once builds in flight finish. Commits
+    /// which are superseded before that are not built.
+    Queue,
+    /// Abort builds in flight, and build the new commit
+    CancelRunning,
+    /// Ignore new commits while a build is in flight. Once no build is in
+    /// flight, the branch is checked again and its head is built if it was
+    /// not built yet.
+    SkipIfRunning,
 }
 
 impl Job {
+    /// Fill in the values of all secrets the job references from the store
+    pub fn resolve_secrets(&mut self, store: &SecretStore) -> Result<(), String> {
+        for v in self.secret_values_mut() {
+            v.resolve(store)?;
+        }
+        for v in self.env.values_mut() {
+            v.resolve(store)?;
+        }
+        Ok(())
+    }
+
+    /// Copy of the job for API responses, with credentials and the webhook
+    /// secret hidden. References to secrets are kept, they only name them.
+    pub fn redacted(&self) -> Job {
+        let mut job = self.clone();
+        for v in job.secret_values_mut() {
+            v.redact();
+        }
+        job
+    }
+
+    /// Values which must not show up in build logs: credentials, the webhook
+    /// secret, and environment variables which reference secrets
+    pub fn secret_strings(&self) -> Vec<String> {
+        let mut job = self.clone();
+        let mut values: Vec<String> = job
+            .secret_values_mut()
+            .into_iter()
+            .map(|v| v.value().to_string())
+            .collect();
+        if let Some(GitAuth::PrivateKeyInline { key, key_env, .. }) = &self.auth {
+            values.extend(GitAuth::inline_key(key, key_env).ok());
+        }
+        values.extend(
+            self.env
+                .values()
+                .filter(|v| matches!(v, SecretValue::Secret { .. }))
+                .map(|v| v.value().to_string()),
+        );
+        values
+    }
+
+    /// Credentials and the webhook secret
+    fn secret_values_mut(&mut self) -> Vec<&mut SecretValue> {
+        let mut values = vec![];
+        match &mut self.auth {
+            Some(GitAuth::PrivateKey { passphrase, .. }) => values.extend(passphrase),
+            Some(GitAuth::PrivateKeyInline {
+                key, passphrase, ..
+            }) => {
+                values.extend(key);
+                values.extend(passphrase);
+            }
+            Some(GitAuth::UserPass { password, .. }) => values.push(password),
+            Some(GitAuth::Token { token, .. }) => values.push(token),
+            Some(GitAuth::SshAgent) | None => {}
+        }
+        if let Some(w) = &mut self.webhook {
+            values.push(&mut w.secret);
+        }
+        values
+    }
+
+    /// Whether SSH host keys are checked against `known_hosts` and
+    /// `host_fingerprints`. They are not checked if neither is set.
+    pub fn verifies_host_keys(&self) -> bool {
+        self.known_hosts.is_some() || !self.host_fingerprints.is_empty()
+    }
+
     pub fn validate(&mut self) -> Result<(), String> {
         if self.repo_url.trim().is_empty() {
             return Err("Repository url is empty.".into());
@@ -128,18 +610,436 @@ impl Job {
             return Err("Poll interval must be > 0.".into());
         }
 
+        if self.max_concurrent_builds.eq(&Some(0)) {
+            return Err("Max concurrent builds must be > 0.".into());
+        }
+
+        if self.timeout_seconds.eq(&Some(0)) {
+            return Err("Build timeout must be > 0.".into());
+        }
+
+        if self.clone_depth.eq(&Some(0)) {
+            return Err("Clone depth must be > 0.".into());
+        }
+
+        if self.clone_depth.is_some() && self.checkout != CheckoutMode::Mirror {
+            return Err("Clone depth is only supported for checkouts from the mirror.".into());
+        }
+
+        if let Some(f) = &self.branches {
+            f.matcher()?;
+        }
+
+        if let Some(GitAuth::PrivateKeyInline { key, key_env, .. }) = &self.auth {
+            GitAuth::inline_key(key, key_env)?;
+        }
+
+        if self.verifies_host_keys() {
+            if !is_ssh_url(&self.repo_url) {
+                return Err("Host keys can only be verified for SSH repository urls.".into());
+            }
+            if self.lfs && !self.host_fingerprints.is_empty() {
+                return Err(
+                    "Host fingerprints are not supported with LFS, use known_hosts.".into(),
+                );
+            }
+            HostKeyVerifier::new(self.known_hosts.as_deref(), &self.host_fingerprints)?;
+        }
+
+        if let Some(w) = &self.webhook {
+            if w.secret.value().trim().is_empty() {
+                return Err("Webhook secret is empty.".into());
+            }
+        }
+
+        for k in self.env.keys() {
+            validate_env_name(k)?;
+        }
+
+        if let Some(path) = &self.env_file {
+            if !Path::new(path).is_absolute() {
+                return Err(format!("Env file path {:?} is not absolute.", path));
+            }
+            read_env_file(Path::new(path))?;
+        }
+
         Ok(())
     }
 }
 
+fn validate_env_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(format!("Invalid environment variable name {:?}.", name));
+    }
+    Ok(())
+}
+
+/// Read environment variables from a file with `KEY=VALUE` lines. Empty
+/// lines and lines starting with `#` are skipped, and values may be quoted.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read env file {:?}: {}", path, e))?;
+    let mut vars = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (k, v) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => {
+                return Err(format!(
+                    "Line {} of env file {:?} is not of the form KEY=VALUE.",
+                    i + 1,
+                    path
+                ))
+            }
+        };
+        let k = k.trim();
+        validate_env_name(k)?;
+        let v = v.trim();
+        let v = if v.len() >= 2
+            && ((v.starts_with('"') && v.ends_with('"'))
+                || (v.starts_with('\'') && v.ends_with('\'')))
+        {
+            &v[1..v.len() - 1]
+        } else {
+            v
+        };
+        vars.push((k.to_string(), v.to_string()));
+    }
+    Ok(vars)
+}
+
+/// A sensitive value in thingy.yaml, given in plain text, or as a reference
+/// `{secret: <name>}` to a secret of the workspace's secret store
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SecretValue {
+    Secret {
+        secret: String,
+        /// value from the secret store, filled in by `Job::resolve_secrets`
+        #[serde(skip)]
+        value: Option<String>,
+    },
+    Plain(String),
+}
+
+/// Shown in API responses instead of plain text secret values
+const REDACTED: &str = "********";
+
+impl SecretValue {
+    /// The value, which is empty for references to secrets which have not
+    /// been resolved
+    pub fn value(&self) -> &str {
+        match self {
+            SecretValue::Secret { value, .. } => value.as_deref().unwrap_or_default(),
+            SecretValue::Plain(v) => v,
+        }
+    }
+
+    fn resolve(&mut self, store: &SecretStore) -> Result<(), String> {
+        if let SecretValue::Secret { secret, value } = self {
+            *value = Some(store.get(secret)?.to_string());
+        }
+        Ok(())
+    }
+
+    fn redact(&mut self) {
+        if let SecretValue::Plain(v) = self {
+            *v = REDACTED.into();
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum GitAuth {
     PrivateKey {
         path: String,
-        passphrase: Option<String>,
+        passphrase: Option<SecretValue>,
+    },
+    /// SSH private key given in the configuration, or in an environment
+    /// variable of thingy
+    PrivateKeyInline {
+        key: Option<SecretValue>,
+        key_env: Option<String>,
+        passphrase: Option<SecretValue>,
     },
+    /// Keys of the SSH agent at `SSH_AUTH_SOCK`
+    SshAgent,
     UserPass {
         username: String,
-        password: String,
+        password: SecretValue,
+    },
+    /// Access token for HTTPS, sent as the password. The username defaults
+    /// to `x-access-token`.
+    Token {
+        token: SecretValue,
+        username: Option<String>,
+    },
+}
+
+impl GitAuth {
+    /// Contents of an inline private key
+    pub fn inline_key(
+        key: &Option<SecretValue>,
+        key_env: &Option<String>,
+    ) -> Result<String, String> {
+        match (key, key_env) {
+            (Some(k), None) => Ok(k.value().to_string()),
+            (None, Some(var)) => std::env::var(var)
+                .map_err(|_| format!("Environment variable {} with private key is not set.", var)),
+            _ => Err("Inline private key needs exactly one of key and key_env.".into()),
+        }
+    }
+}
+
+/// State of a single build. Builds move from `Queued` through
+/// `CloningRepo` and `Running` into one of the finished states.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BuildStatus {
+    Queued,
+    CloningRepo,
+    Running,
+    Succeeded,
+    /// Build script exited with a non-zero code, or was killed by a signal
+    Failed {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// Build was stopped before it finished, by a user or because its job
+    /// was removed
+    Aborted {
+        reason: Option<String>,
+    },
+    TimedOut,
+    /// Thingy stopped while the build was running
+    Interrupted,
+    /// Build script could not be started
+    SpawnFailed,
+    /// Repository could not be checked out, including its submodules and
+    /// LFS files
+    CloneFailed {
+        error: Option<String>,
     },
+    /// The saved state of the build was lost, and only its directory was
+    /// found again
+    Unknown,
+}
+
+impl BuildStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self,
+            BuildStatus::Queued | BuildStatus::CloningRepo | BuildStatus::Running
+        )
+    }
+
+    /// Map status strings written by older versions of thingy. Versions
+    /// before exit statuses were recorded wrote `finished` for every build
+    /// which ended, whatever its outcome, so `finished` is `Unknown`.
+    fn from_legacy(status: &str, code: Option<i32>, signal: Option<i32>) -> Self {
+        match status {
+            "building" => BuildStatus::Running,
+            "success" => BuildStatus::Succeeded,
+            "failed" | "killed" | "error" => BuildStatus::Failed { code, signal },
+            "aborted" => BuildStatus::Aborted { reason: None },
+            "spawn_failed" => BuildStatus::SpawnFailed,
+            "clone_failed" => BuildStatus::CloneFailed { error: None },
+            _ => BuildStatus::Unknown,
+        }
+    }
+}
+
+/// What caused a build to start
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildTrigger {
+    /// A new commit was found while polling the repository
+    Poll,
+    /// A user started the build
+    Manual,
+    /// A webhook reported a push
+    Webhook,
+}
+
+impl BuildTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BuildTrigger::Poll => "poll",
+            BuildTrigger::Manual => "manual",
+            BuildTrigger::Webhook => "webhook",
+        }
+    }
+}
+
+/// A change of a build's status, and when it happened
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BuildTransition {
+    pub status: BuildStatus,
+    pub at: DateTime<Utc>,
+}
+
+/// A build of a branch, as saved in the branch's data.json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "StoredBuildDetails")]
+pub struct BuildDetails {
+    pub build_num: u64,
+    pub commit_hash: Option<String>,
+    pub trigger: Option<BuildTrigger>,
+    pub status: BuildStatus,
+    /// every status this build has been in, oldest first
+    pub transitions: Vec<BuildTransition>,
+    /// thingy process which queued the build, see `run_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+impl BuildDetails {
+    pub fn new(
+        build_num: u64,
+        commit_hash: Option<String>,
+        trigger: BuildTrigger,
+        status: BuildStatus,
+    ) -> Self {
+        BuildDetails {
+            build_num,
+            commit_hash,
+            trigger: Some(trigger),
+            status: status.clone(),
+            transitions: vec![BuildTransition {
+                status,
+                at: Utc::now(),
+            }],
+            run_id: Some(run_id().to_string()),
+        }
+    }
+
+    /// Whether the build is queued or running in another thingy process,
+    /// which has most likely stopped
+    pub fn is_orphaned(&self) -> bool {
+        !self.status.is_finished() && self.run_id.as_deref() != Some(run_id())
+    }
+
+    /// Move this build to a new status. Finished builds keep their status.
+    pub fn set_status(&mut self, status: BuildStatus) {
+        if self.status == status || self.status.is_finished() {
+            return;
+        }
+        self.status = status.clone();
+        self.transitions.push(BuildTransition {
+            status,
+            at: Utc::now(),
+        });
+    }
+}
+
+/// Identifies this thingy process in saved builds, so that builds left
+/// unfinished by an earlier process can be told apart from its own
+pub fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+    RUN_ID.get_or_init(|| format!("{}-{}", std::process::id(), Utc::now().timestamp_millis()))
+}
+
+/// Build details as found in data.json, which may have been written by an
+/// older version of thingy with a plain string status
+#[derive(Deserialize)]
+struct StoredBuildDetails {
+    build_num: u64,
+    commit_hash: Option<String>,
+    trigger: Option<BuildTrigger>,
+    status: StoredBuildStatus,
+    #[serde(default)]
+    transitions: Vec<BuildTransition>,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    #[serde(default)]
+    run_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredBuildStatus {
+    Current(BuildStatus),
+    Legacy(String),
+}
+
+impl From<StoredBuildDetails> for BuildDetails {
+    fn from(stored: StoredBuildDetails) -> Self {
+        let status = match stored.status {
+            StoredBuildStatus::Current(s) => s,
+            StoredBuildStatus::Legacy(s) => {
+                BuildStatus::from_legacy(&s, stored.exit_code, stored.signal)
+            }
+        };
+        BuildDetails {
+            build_num: stored.build_num,
+            commit_hash: stored.commit_hash,
+            trigger: stored.trigger,
+            status,
+            transitions: stored.transitions,
+            run_id: stored.run_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct DataFile {
+        builds: Vec<BuildDetails>,
+    }
+
+    fn statuses(data_json: &str) -> Vec<BuildStatus> {
+        let data: DataFile = serde_json::from_str(data_json).unwrap();
+        data.builds.into_iter().map(|b| b.status).collect()
+    }
+
+    #[test]
+    fn reads_statuses_without_exit_status() {
+        let data_json = r#"{
+            "last_seen_commit": "c2",
+            "builds": [
+                {"build_num": 1, "commit_hash": "c1", "status": "finished"},
+                {"build_num": 2, "commit_hash": "c2", "status": "building"}
+            ]
+        }"#;
+        assert_eq!(
+            statuses(data_json),
+            vec![BuildStatus::Unknown, BuildStatus::Running]
+        );
+    }
+
+    #[test]
+    fn reads_statuses_with_exit_status() {
+        let data_json = r#"{
+            "last_seen_commit": "c3",
+            "builds": [
+                {"build_num": 1, "commit_hash": "c1", "status": "success",
+                 "exit_code": 0, "signal": null},
+                {"build_num": 2, "commit_hash": "c2", "status": "failed",
+                 "exit_code": 2, "signal": null},
+                {"build_num": 3, "commit_hash": "c3", "status": "killed",
+                 "exit_code": null, "signal": 9}
+            ]
+        }"#;
+        assert_eq!(
+            statuses(data_json),
+            vec![
+                BuildStatus::Succeeded,
+                BuildStatus::Failed {
+                    code: Some(2),
+                    signal: None
+                },
+                BuildStatus::Failed {
+                    code: None,
+                    signal: Some(9)
+                },
+            ]
+        );
+    }
 }
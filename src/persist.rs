@@ -0,0 +1,83 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Suffix of the previous version of a file written with `write_atomic`
+const BACKUP_SUFFIX: &str = ".bak";
+
+/// Where `write_atomic` keeps the previous version of a file
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(BACKUP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Replace the contents of a file, so that a crash or power cut leaves
+/// either the old or the new version on disk, never a partly written one.
+/// The contents are written to a temporary file which is synced to disk and
+/// then renamed over the file. The previous version is kept as a backup,
+/// see `backup_path`.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_file(path, contents, true)
+}
+
+fn replace_file(path: &Path, contents: &[u8], keep_backup: bool) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    if keep_backup && path.exists() {
+        // a hard link keeps the file in place while the backup is made
+        let backup = backup_path(path);
+        let _ = fs::remove_file(&backup);
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+    }
+    fs::rename(&tmp_path, path)?;
+    // the rename is only durable once the directory is synced
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+/// Read and parse a file written with `write_atomic`, falling back to its
+/// previous version if the file is missing or can not be parsed. The file is
+/// then restored from the previous version, so that the next write does not
+/// replace the previous version with the unreadable file.
+pub fn read_with_backup<T, F>(path: &Path, parse: F) -> Result<T, String>
+where
+    F: Fn(&str) -> Result<T, String>,
+{
+    let read = |p: &Path| {
+        let contents = fs::read_to_string(p).map_err(|e| e.to_string())?;
+        parse(&contents).map(|value| (value, contents))
+    };
+    let err = match read(path) {
+        Ok((value, _)) => return Ok(value),
+        Err(err) => err,
+    };
+    let backup = backup_path(path);
+    let (value, contents) =
+        read(&backup).map_err(|_| format!("Could not read {:?}: {}", path, err))?;
+    eprintln!(
+        "Could not read {:?}: {}. Using the previous version from {:?}.",
+        path, err, backup
+    );
+    if let Err(err) = replace_file(path, contents.as_bytes(), false) {
+        eprintln!("Could not restore {:?}: {}", path, err);
+    }
+    Ok(value)
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::persist::{backup_path, write_atomic};
+
+/// Name of the encrypted secrets file in the workspace directory
+pub const SECRETS_FILE_NAME: &str = "secrets.json";
+
+/// Environment variable with the master key of the secret store
+const MASTER_KEY_ENV: &str = "THINGY_SECRETS_KEY";
+/// Environment variable with the path of a file containing the master key
+const MASTER_KEY_FILE_ENV: &str = "THINGY_SECRETS_KEY_FILE";
+/// Variables of thingy which are not passed on to builds
+pub const MASTER_KEY_VARS: &[&str] = &[MASTER_KEY_ENV, MASTER_KEY_FILE_ENV];
+
+const KDF_ITERATIONS: u32 = 200_000;
+const SALT_LEN: usize = 16;
+
+/// Named secrets of a workspace, stored in `secrets.json` encrypted with a
+/// key derived from the master key. Secrets are only held decrypted in memory.
+pub struct SecretStore {
+    path: PathBuf,
+    master_key: Option<String>,
+    secrets: BTreeMap<String, String>,
+}
+
+/// Contents of `secrets.json`
+#[derive(Serialize, Deserialize)]
+struct SecretsFile {
+    version: u32,
+    /// PBKDF2-HMAC-SHA256 iterations used to derive the key
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    /// the secrets as a JSON object, encrypted with ChaCha20-Poly1305
+    data: String,
+}
+
+impl SecretStore {
+    /// Open the secret store of a workspace. The master key is read from
+    /// `THINGY_SECRETS_KEY`, or the file named by `THINGY_SECRETS_KEY_FILE`.
+    /// Workspaces without secrets do not need a master key.
+    pub fn open(workspace_dir: &Path) -> Result<Self, String> {
+        let master_key = read_master_key()?;
+        let path = workspace_dir.join(SECRETS_FILE_NAME);
+        let mut store = SecretStore {
+            path,
+            master_key,
+            secrets: BTreeMap::new(),
+        };
+        if !store.path.exists() {
+            return Ok(store);
+        }
+        let contents = std::fs::read_to_string(&store.path)
+            .map_err(|e| format!("Could not read {:?}: {}", store.path, e))?;
+        let file: SecretsFile = serde_json::from_str(&contents)
+            .map_err(|e| format!("{:?} is not a valid secrets file: {}", store.path, e))?;
+        if file.version != 1 {
+            return Err(format!(
+                "{:?} has unsupported version {}",
+                store.path, file.version
+            ));
+        }
+        let master_key = store.master_key()?;
+        let decode = |s: &str| {
+            base64::decode(s).map_err(|_| format!("{:?} is not a valid secrets file", store.path))
+        };
+        let salt = decode(&file.salt)?;
+        let nonce = decode(&file.nonce)?;
+        if nonce.len() != 12 {
+            return Err(format!("{:?} is not a valid secrets file", store.path));
+        }
+        let cipher = cipher(master_key, &salt, file.iterations);
+        let plain = cipher
+            .decrypt(Nonce::from_slice(&nonce), decode(&file.data)?.as_ref())
+            .map_err(|_| {
+                format!(
+                    "Could not decrypt {:?}, is the master key correct?",
+                    store.path
+                )
+            })?;
+        store.secrets = serde_json::from_slice(&plain)
+            .map_err(|e| format!("{:?} is not a valid secrets file: {}", store.path, e))?;
+        Ok(store)
+    }
+
+    fn master_key(&self) -> Result<&str, String> {
+        self.master_key.as_deref().ok_or_else(|| {
+            format!(
+                "No master key for secrets, set {} or {}",
+                MASTER_KEY_ENV, MASTER_KEY_FILE_ENV
+            )
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&str, String> {
+        self.secrets
+            .get(name)
+            .map(|s| s.as_str())
+            .ok_or_else(|| format!("Secret {:?} does not exist", name))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.secrets.keys()
+    }
+
+    /// Add or replace a secret, and write the store to disk
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        validate_secret_name(name)?;
+        self.secrets.insert(name.to_string(), value.to_string());
+        self.save()
+    }
+
+    /// Remove a secret, and write the store to disk. Returns whether the
+    /// secret existed.
+    pub fn remove(&mut self, name: &str) -> Result<bool, String> {
+        if self.secrets.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Encrypt the secrets with a new salt and nonce, and write them to disk
+    fn save(&self) -> Result<(), String> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = cipher(self.master_key()?, &salt, KDF_ITERATIONS);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plain = serde_json::to_vec(&self.secrets).map_err(|e| e.to_string())?;
+        let data = cipher
+            .encrypt(&nonce, plain.as_ref())
+            .map_err(|_| "Could not encrypt secrets".to_string())?;
+        let file = SecretsFile {
+            version: 1,
+            iterations: KDF_ITERATIONS,
+            salt: base64::encode(salt),
+            nonce: base64::encode(nonce),
+            data: base64::encode(data),
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+        write_atomic(&self.path, json.as_bytes())
+            .map_err(|e| format!("Could not write {:?}: {}", self.path, e))?;
+        // the previous version would keep removed and replaced secrets around
+        let _ = std::fs::remove_file(backup_path(&self.path));
+        Ok(())
+    }
+}
+
+/// Shows the names of the secrets, but not their values
+impl std::fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStore")
+            .field("path", &self.path)
+            .field("names", &self.secrets.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn read_master_key() -> Result<Option<String>, String> {
+    if let Ok(key) = std::env::var(MASTER_KEY_ENV) {
+        return Ok(Some(key));
+    }
+    if let Ok(path) = std::env::var(MASTER_KEY_FILE_ENV) {
+        let key = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read master key file {:?}: {}", path, e))?;
+        return Ok(Some(key.trim_end().to_string()));
+    }
+    Ok(None)
+}
+
+fn cipher(master_key: &str, salt: &[u8], iterations: u32) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(master_key.as_bytes(), salt, iterations, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn validate_secret_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err(format!(
+            "Invalid secret name {:?}, use letters, digits, '_', '-' and '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Manage the secrets of a workspace from the command line:
+/// `list`, `set <name>` with the value read from stdin, or `remove <name>`
+pub fn run_command(workspace_dir: &Path, args: &[String]) -> Result<(), String> {
+    let mut store = SecretStore::open(workspace_dir)?;
+    match args {
+        [cmd] if cmd == "list" => {
+            for name in store.names() {
+                println!("{}", name);
+            }
+        }
+        [cmd, name] if cmd == "set" => {
+            let mut value = String::new();
+            std::io::stdin()
+                .read_to_string(&mut value)
+                .map_err(|e| format!("Could not read secret from stdin: {}", e))?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            let value = value.strip_suffix('\r').unwrap_or(value);
+            store.set(name, value)?;
+        }
+        [cmd, name] if cmd == "remove" => {
+            if !store.remove(name)? {
+                return Err(format!("Secret {:?} does not exist", name));
+            }
+        }
+        _ => return Err(SECRETS_USAGE.into()),
+    }
+    Ok(())
+}
+
+pub const SECRETS_USAGE: &str =
+    "Usage: thingy secrets <path to workspace dir> list | set <name> | remove <name>";
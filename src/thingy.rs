@@ -1,33 +1,100 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs::create_dir_all,
-    io::{Error, ErrorKind},
+    io::Error,
     path::PathBuf,
 };
 
-use crate::{job_actor::JobActor, models::*};
+use crate::{
+    branch_actor::{BranchActor, StartQueuedBuildMsg},
+    git_worker::GitWorker,
+    job_actor::{JobActor, StopJobMsg},
+    models::*,
+    persist::write_atomic,
+    secrets::SecretStore,
+};
 use actix::prelude::*;
 
+#[derive(Debug)]
 pub struct Thingy {
     pub workpace: Workspace,
     pub dir: PathBuf,
     pub job_actors: HashMap<String, Addr<JobActor>>,
+    /// secrets which jobs can reference
+    secrets: SecretStore,
+    /// pool of threads running git operations for all jobs
+    git: Addr<GitWorker>,
+    /// builds waiting for a build slot, oldest first
+    queue: VecDeque<BuildSlot>,
+    /// builds holding a build slot
+    running: Vec<BuildSlot>,
+}
+
+/// A build which is waiting for, or holding a build slot
+#[derive(Debug)]
+struct BuildSlot {
+    job: String,
+    branch: String,
+    build_num: u64,
+    addr: Addr<BranchActor>,
+}
+
+impl BuildSlot {
+    fn is_build(&self, job: &str, branch: &str, build_num: u64) -> bool {
+        self.job == job && self.branch == branch && self.build_num == build_num
+    }
 }
 
 impl Thingy {
-    pub fn new(workpace: Workspace, dir: PathBuf) -> Self {
+    pub fn new(workpace: Workspace, dir: PathBuf, secrets: SecretStore) -> Self {
         Thingy {
             workpace,
             dir,
             job_actors: HashMap::new(),
+            secrets,
+            git: GitWorker::start_pool(),
+            queue: VecDeque::new(),
+            running: vec![],
+        }
+    }
+
+    /// Start queued builds, in the order they were queued, while the global
+    /// and per-job limits allow
+    fn start_queued_builds(&mut self) {
+        // builds of removed jobs or branches will never report back
+        self.queue.retain(|b| b.addr.connected());
+        self.running.retain(|b| b.addr.connected());
+
+        let mut idx = 0;
+        while idx < self.queue.len() {
+            if let Some(max) = self.workpace.max_concurrent_builds {
+                if self.running.len() >= max {
+                    return;
+                }
+            }
+            let job = &self.queue[idx].job;
+            let job_max = self
+                .workpace
+                .jobs
+                .iter()
+                .find(|j| &j.name == job)
+                .and_then(|j| j.max_concurrent_builds);
+            let job_running = self.running.iter().filter(|b| &b.job == job).count();
+            if job_max.is_some_and(|max| job_running >= max) {
+                idx += 1;
+                continue;
+            }
+            let slot = self.queue.remove(idx).unwrap();
+            slot.addr.do_send(StartQueuedBuildMsg(slot.build_num));
+            self.running.push(slot);
         }
     }
 
     pub fn sync_ws_to_disk(&self) -> Result<(), std::io::Error> {
         let file_path = self.dir.join("thingy.yaml");
         let yaml = serde_yaml::to_string(&self.workpace)
-            .map_err(|_e| Error::new(ErrorKind::Other, "Could not write yaml"))?;
-        std::fs::write(&file_path, yaml)?;
+            .map_err(|_e| Error::other("Could not write yaml"))?;
+        write_atomic(&file_path, yaml.as_bytes())?;
 
         Ok(())
     }
@@ -53,13 +120,33 @@ pub struct AddJobMsg(pub Job);
 #[rtype(result = "Result<(), std::io::Error>")]
 pub struct RemoveJobMsg(pub String);
 
+/// Queue a build, which will be started with a `StartQueuedBuildMsg` to the
+/// branch actor once a build slot is free
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct QueueBuildMsg {
+    pub job: String,
+    pub branch: String,
+    pub build_num: u64,
+    pub addr: Addr<BranchActor>,
+}
+
+/// A build has finished or was aborted, and no longer needs a build slot
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct BuildDoneMsg {
+    pub job: String,
+    pub branch: String,
+    pub build_num: u64,
+}
+
 impl Actor for Thingy {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Context<Self>) {
         for j in &self.workpace.jobs {
             let d = self.dir.join(j.name.clone());
-            let ja = JobActor::new(j.clone(), d).start();
+            let ja = JobActor::new(j.clone(), d, _ctx.address(), self.git.clone()).start();
             self.job_actors.insert(j.name.clone(), ja);
         }
     }
@@ -71,7 +158,9 @@ impl Handler<GetJobsMsg> for Thingy {
     type Result = Result<GetJobResponse, Error>;
 
     fn handle(&mut self, _msg: GetJobsMsg, _ctx: &mut Self::Context) -> Self::Result {
-        Ok(GetJobResponse(self.workpace.jobs.clone()))
+        Ok(GetJobResponse(
+            self.workpace.jobs.iter().map(|j| j.redacted()).collect(),
+        ))
     }
 }
 
@@ -93,8 +182,11 @@ impl Handler<AddJobMsg> for Thingy {
 
     fn handle(&mut self, msg: AddJobMsg, _ctx: &mut Self::Context) -> Self::Result {
         let mut job = msg.0;
-        if let Err(s) = job.validate() {
-            return Err(Error::new(ErrorKind::Other, s.as_str()));
+        if let Err(s) = job
+            .resolve_secrets(&self.secrets)
+            .and_then(|_| job.validate())
+        {
+            return Err(Error::other(s.as_str()));
         }
         if self
             .workpace
@@ -103,16 +195,13 @@ impl Handler<AddJobMsg> for Thingy {
             .find(|j| j.name.eq(&job.name))
             .is_some()
         {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Job with this name already exists",
-            ));
+            return Err(Error::other("Job with this name already exists"));
         }
         self.workpace.jobs.push(job.clone());
 
         let d = self.dir.join(job.name.clone());
         create_dir_all(&d)?;
-        let ja = JobActor::new(job.clone(), d).start();
+        let ja = JobActor::new(job.clone(), d, _ctx.address(), self.git.clone()).start();
         self.job_actors.insert(job.name.clone(), ja);
 
         self.sync_ws_to_disk()
@@ -123,6 +212,9 @@ impl Handler<RemoveJobMsg> for Thingy {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, _msg: RemoveJobMsg, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(ja) = self.job_actors.get(&_msg.0) {
+            ja.do_send(StopJobMsg);
+        }
         // Remove the job actor's address from this actor. This is the only place to hold job actor's address,
         // so removing it will stop the job actor.
         self.job_actors = self
@@ -142,3 +234,29 @@ impl Handler<RemoveJobMsg> for Thingy {
         self.sync_ws_to_disk()
     }
 }
+
+impl Handler<QueueBuildMsg> for Thingy {
+    type Result = ();
+
+    fn handle(&mut self, msg: QueueBuildMsg, _ctx: &mut Self::Context) -> Self::Result {
+        self.queue.push_back(BuildSlot {
+            job: msg.job,
+            branch: msg.branch,
+            build_num: msg.build_num,
+            addr: msg.addr,
+        });
+        self.start_queued_builds();
+    }
+}
+
+impl Handler<BuildDoneMsg> for Thingy {
+    type Result = ();
+
+    fn handle(&mut self, msg: BuildDoneMsg, _ctx: &mut Self::Context) -> Self::Result {
+        self.queue
+            .retain(|b| !b.is_build(&msg.job, &msg.branch, msg.build_num));
+        self.running
+            .retain(|b| !b.is_build(&msg.job, &msg.branch, msg.build_num));
+        self.start_queued_builds();
+    }
+}
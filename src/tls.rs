@@ -0,0 +1,129 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+};
+
+use actix_web::{http::header, rt::signal::unix, web, HttpRequest, HttpResponse};
+use rustls::{
+    internal::pemfile,
+    sign::{self, CertifiedKey},
+    ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig,
+};
+
+/// Environment variable with the path of the PEM certificate chain
+const CERT_PATH_ENV: &str = "TLS_CERT_PATH";
+/// Environment variable with the path of the PEM private key
+const KEY_PATH_ENV: &str = "TLS_KEY_PATH";
+
+/// Certificate and key of the HTTPS server, which are read again from their
+/// files on SIGHUP, so that renewed certificates are used without a restart
+pub struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    key: RwLock<CertifiedKey>,
+}
+
+impl ReloadingCertResolver {
+    /// Load the certificate and key named by `TLS_CERT_PATH` and
+    /// `TLS_KEY_PATH`, if TLS is configured
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let (cert_path, key_path) = match (
+            std::env::var(CERT_PATH_ENV).ok(),
+            std::env::var(KEY_PATH_ENV).ok(),
+        ) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(format!(
+                    "Both {} and {} must be set to enable TLS",
+                    CERT_PATH_ENV, KEY_PATH_ENV
+                ))
+            }
+        };
+        let key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Some(ReloadingCertResolver {
+            cert_path,
+            key_path,
+            key: RwLock::new(key),
+        }))
+    }
+
+    /// Read the certificate and key again. The old ones are kept if the new
+    /// ones can not be loaded.
+    pub fn reload(&self) -> Result<(), String> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.key.write().unwrap() = key;
+        Ok(())
+    }
+
+    /// Rustls configuration using this resolver
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.clone();
+        config
+    }
+
+    /// Reload the certificate and key whenever thingy receives SIGHUP
+    pub fn reload_on_sighup(self: Arc<Self>) -> std::io::Result<()> {
+        let mut hangup = unix::signal(unix::SignalKind::hangup())?;
+        actix_web::rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match self.reload() {
+                    Ok(()) => println!("Reloaded TLS certificate from {}", self.cert_path),
+                    Err(err) => eprintln!("Could not reload TLS certificate: {}", err),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Could not read {}: {}", path, e))
+    };
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|_| format!("{} is not a valid PEM certificate file", cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("{} contains no certificates", cert_path));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("{} contains no PKCS#8 or RSA private key", key_path))?;
+    let key = sign::any_supported_type(key)
+        .map_err(|_| format!("The private key in {} is not supported", key_path))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Redirect a plain HTTP request to the same host and path on the HTTPS port
+pub async fn redirect_to_https(req: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let https_port = *https_port.get_ref();
+    let info = req.connection_info();
+    let host = info.host();
+    // strip the port, keeping IPv6 addresses like [::1] whole
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, req.uri())
+    } else {
+        format!("https://{}:{}{}", host, https_port, req.uri())
+    };
+    HttpResponse::MovedPermanently()
+        .set_header(header::LOCATION, location)
+        .finish()
+}
@@ -0,0 +1,93 @@
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::models::GitRef;
+
+/// Signature headers sent along with a webhook request. Which one is set
+/// depends on the service sending the webhook.
+#[derive(Debug, Default)]
+pub struct WebhookSignature {
+    /// `X-Hub-Signature-256` header sent by Github, `sha256=<hex hmac>`
+    pub github: Option<String>,
+    /// `X-Gitea-Signature` or `X-Forgejo-Signature` header, `<hex hmac>`
+    pub gitea: Option<String>,
+    /// `X-Gitlab-Token` header, the configured secret itself
+    pub gitlab_token: Option<String>,
+}
+
+/// A push to a branch or tag, as reported by a webhook
+#[derive(Debug)]
+pub struct PushEvent {
+    pub git_ref: GitRef,
+    pub commit_hash: String,
+}
+
+/// The parts of Github, Gitea/Forgejo and Gitlab push payloads which thingy
+/// uses. All three services use the same field names for these.
+#[derive(Deserialize)]
+struct PushPayload {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    after: Option<String>,
+}
+
+/// Check that a webhook request was signed with, or contains, the secret
+pub fn verify_signature(
+    signature: &WebhookSignature,
+    body: &[u8],
+    secret: &str,
+) -> Result<(), String> {
+    if let Some(sig) = &signature.github {
+        let sig = sig
+            .strip_prefix("sha256=")
+            .ok_or("Unsupported signature algorithm")?;
+        return verify_hmac(sig, body, secret);
+    }
+    if let Some(sig) = &signature.gitea {
+        return verify_hmac(sig, body, secret);
+    }
+    if let Some(token) = &signature.gitlab_token {
+        if constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+            return Ok(());
+        }
+        return Err("Invalid token".into());
+    }
+    Err("Request is not signed".into())
+}
+
+fn verify_hmac(hex_sig: &str, body: &[u8], secret: &str) -> Result<(), String> {
+    let sig = hex::decode(hex_sig.trim()).map_err(|_| "Malformed signature")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|e| e.to_string())?;
+    mac.update(body);
+    mac.verify_slice(&sig)
+        .map_err(|_| "Invalid signature".into())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Get the pushed ref and commit from a webhook payload. Returns `None`
+/// for events which should not trigger a build, like pings, pushes to
+/// refs thingy does not build, and deletions.
+pub fn parse_push(body: &[u8]) -> Result<Option<PushEvent>, String> {
+    let payload: PushPayload =
+        serde_json::from_slice(body).map_err(|e| format!("Invalid payload: {}", e))?;
+    let (git_ref, after) = match (payload.git_ref, payload.after) {
+        (Some(r), Some(a)) => (r, a),
+        _ => return Ok(None),
+    };
+    let git_ref = match GitRef::parse(&git_ref) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    // an all-zero hash means the ref was deleted
+    if after.is_empty() || after.chars().all(|c| c == '0') {
+        return Ok(None);
+    }
+    Ok(Some(PushEvent {
+        git_ref,
+        commit_hash: after,
+    }))
+}